@@ -0,0 +1,186 @@
+use crate::error::SyncError;
+use anyhow::{format_err, Context};
+use simplelog::debug;
+use std::fs::{read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// The length of backup timestamps like `2024-01-02T15-04-05`.
+const TIMESTAMP_LENGTH: usize = 19;
+
+/// How replaced files are backed up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupMode {
+    /// Keep a single backup, replacing the previous one.
+    Overwrite,
+    /// Keep every backup with the UTC time it was made, e.g. `name.ext.2024-01-02T15-04-05.bak`.
+    Timestamped,
+    /// Keep every backup with the next free number, e.g. `name.ext.1.bak`.
+    Numbered,
+    /// Snapshot each destination on btrfs before anything is written instead of backing up files,
+    /// the snapshot is named like a timestamped backup of the destination.
+    BtrfsSnapshot,
+}
+
+impl FromStr for BackupMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "overwrite" => Ok(BackupMode::Overwrite),
+            "timestamped" => Ok(BackupMode::Timestamped),
+            "numbered" => Ok(BackupMode::Numbered),
+            "btrfs-snapshot" => Ok(BackupMode::BtrfsSnapshot),
+            other => Err(format_err!(
+                "Unknown backup mode {}, expected overwrite, timestamped, numbered or btrfs-snapshot",
+                other
+            )),
+        }
+    }
+}
+
+impl BackupMode {
+    /// Gets the path to back the destination up to, the suffix is appended to the full file name.
+    pub fn backup_path(&self, destination: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+        let file_name = destination
+            .file_name()
+            .context("Get destination file name")?
+            .to_string_lossy();
+
+        match self {
+            BackupMode::Overwrite => {
+                Ok(destination.with_file_name(format!("{}.{}", file_name, suffix)))
+            }
+            BackupMode::Timestamped | BackupMode::BtrfsSnapshot => {
+                let timestamp = OffsetDateTime::now_utc()
+                    .format(format_description!(
+                        "[year]-[month]-[day]T[hour]-[minute]-[second]"
+                    ))
+                    .context("Format backup timestamp")?;
+
+                Ok(destination.with_file_name(format!("{}.{}.{}", file_name, timestamp, suffix)))
+            }
+            // Counting on from the newest keeps the numbers in order after old backups were removed.
+            BackupMode::Numbered => {
+                let last = self
+                    .existing_backups(destination, suffix)?
+                    .last()
+                    .and_then(|(number, _)| number.parse::<u64>().ok())
+                    .unwrap_or(0);
+
+                Ok(destination.with_file_name(format!("{}.{}.{}", file_name, last + 1, suffix)))
+            }
+        }
+    }
+
+    /// Removes the oldest backups of a file beyond the number to keep.
+    ///
+    /// Only files named exactly like this mode names the backups of the file are considered,
+    /// so other files ending in the suffix are never touched.
+    pub fn prune_backups(
+        &self,
+        destination: &Path,
+        suffix: &str,
+        keep: usize,
+    ) -> anyhow::Result<()> {
+        let backups = self.existing_backups(destination, suffix)?;
+        let excess = backups.len().saturating_sub(keep);
+        for (_, backup) in backups.into_iter().take(excess) {
+            debug!("Removing old backup {}", backup.display());
+            remove_file(&backup)
+                .with_context(|| format!("Remove old backup {}", backup.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists the backups of a file named the way this mode names them, oldest first.
+    fn existing_backups(
+        &self,
+        destination: &Path,
+        suffix: &str,
+    ) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let is_part = |part: &str| match self {
+            BackupMode::Numbered => !part.is_empty() && part.bytes().all(|c| c.is_ascii_digit()),
+            BackupMode::Timestamped => {
+                part.len() == TIMESTAMP_LENGTH
+                    && part.bytes().enumerate().all(|(i, c)| match i {
+                        4 | 7 | 13 | 16 => c == b'-',
+                        10 => c == b'T',
+                        _ => c.is_ascii_digit(),
+                    })
+            }
+            // A single backup is kept or the whole destination is snapshotted.
+            BackupMode::Overwrite | BackupMode::BtrfsSnapshot => false,
+        };
+
+        let file_name = destination
+            .file_name()
+            .context("Get destination file name")?
+            .to_string_lossy();
+        let parent = destination.parent().context("Get destination folder")?;
+        let prefix = format!("{}.", file_name);
+        let suffix = format!(".{}", suffix);
+
+        let mut backups = read_dir(parent)
+            .with_context(|| format!("Read {}", parent.display()))?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let part = name.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+                // Numbers are compared by value, timestamps sort the same as the time they were made.
+                let order = match self {
+                    BackupMode::Numbered => format!("{:0>20}", part),
+                    _ => part.to_string(),
+                };
+                is_part(part).then(|| (order, entry.path()))
+            })
+            .collect::<Vec<_>>();
+        backups.sort();
+
+        Ok(backups)
+    }
+}
+
+/// Takes a read-only btrfs snapshot of a destination next to it, failing when it isn't a subvolume
+/// so a sync never runs without the backup it was configured with.
+pub fn snapshot_destination(destination: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let output = Command::new("btrfs")
+        .args(["subvolume", "show"])
+        .arg(destination)
+        .output()
+        .map_err(|err| {
+            SyncError::ConfigError(format_err!(
+                "Run btrfs, btrfs-progs has to be installed for btrfs snapshots -> {}",
+                err
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(SyncError::ConfigError(format_err!(
+            "Destination {} isn't a btrfs subvolume so it can't be snapshotted, use another backup mode -> {}",
+            destination.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+
+    let snapshot = BackupMode::BtrfsSnapshot.backup_path(destination, suffix)?;
+    let output = Command::new("btrfs")
+        .args(["subvolume", "snapshot", "-r"])
+        .arg(destination)
+        .arg(&snapshot)
+        .output()
+        .context("Run btrfs")?;
+    if !output.status.success() {
+        return Err(format_err!(
+            "Snapshot {} -> {}",
+            destination.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(snapshot)
+}
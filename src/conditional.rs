@@ -0,0 +1,52 @@
+/// The block helper that skips a whole file when it wraps the file and its condition is false.
+pub const CONDITION_HELPER: &str = "server-sync-if";
+
+/// Gets a template that renders to `1` when a condition like `server-sync-if` takes is true, and to nothing otherwise.
+pub fn condition_template(condition: &str) -> String {
+    format!(
+        "{{{{#{helper} {condition}}}}}1{{{{/{helper}}}}}",
+        helper = CONDITION_HELPER,
+        condition = condition
+    )
+}
+
+/// A template wrapped whole in `{{#server-sync-if condition}}...{{/server-sync-if}}`.
+pub struct Conditional<'t> {
+    /// The condition deciding if the file is written.
+    pub condition: &'t str,
+    /// The template inside the block.
+    pub body: &'t str,
+}
+
+impl<'t> Conditional<'t> {
+    /// Finds the block wrapping a template, surrounding whitespace is allowed and the line break
+    /// after the opening tag is left out of the body so the directive can sit on its own line.
+    ///
+    /// Templates that use the helper anywhere else are rendered as they are.
+    pub fn find(contents: &'t str) -> Option<Self> {
+        let trimmed = contents.trim();
+        let close = format!("{{{{/{}}}}}", CONDITION_HELPER);
+        let rest = trimmed
+            .strip_prefix("{{#")?
+            .strip_prefix(CONDITION_HELPER)?
+            .strip_suffix(close.as_str())?;
+        if !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        let end = rest.find("}}")?;
+        let body = &rest[end + 2..];
+        // Another block of the helper inside means this one doesn't wrap the whole file.
+        if body.contains(close.as_str()) {
+            return None;
+        }
+
+        Some(Conditional {
+            condition: rest[..end].trim(),
+            body: body
+                .strip_prefix("\r\n")
+                .or_else(|| body.strip_prefix('\n'))
+                .unwrap_or(body),
+        })
+    }
+}
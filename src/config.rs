@@ -1,25 +1,262 @@
+use crate::backup::BackupMode;
+use crate::delimiters::{parse_rules, Delimiters};
+use crate::diff::DiffFormat;
+use crate::encoding::{load_manifest, OutputEncoding, ENCODING_MANIFEST};
+use crate::eol::LineEnding;
+use crate::error::SyncError;
+use crate::hooks::HOOK_FILE;
+use crate::includes::{self, INCLUDE_FILE};
+use crate::interpolate::interpolate;
+use crate::manifest::read_manifest;
+use crate::merger::{MergeDriver, MERGE_MANIFEST};
+use crate::permissions::{native, PermissionManager};
+use crate::rsync::Backend;
+use crate::sync_ignore::{SyncIgnore, IGNORE_FILE, RAW_COPY_FILE};
+use crate::vars::{merge_vars, read_vars_file, vars_files};
 use envfile::EnvFile;
-use std::borrow::Borrow;
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
+use std::borrow::{Borrow, Cow};
 
 use anyhow::{format_err, Context};
-use clap::builder::TypedValueParser;
+use clap::parser::ValueSource;
 use clap::ArgMatches;
+use serde_json::{Map, Value};
 use simplelog::{debug, trace, warn};
 use std::collections::BTreeMap;
-use std::fmt::{Debug, Display, Formatter};
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::fmt::{Debug, Formatter};
+use std::fs::read_dir;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+pub const EXPORTS_MANIFEST: &str = ".server-sync-exports";
+pub const CONTEXT_VARS_FILE: &str = "vars.toml";
+/// Files in a directory with this marker, or in any directory below it, are copied without templating.
+pub const NO_FORMAT_MARKER: &str = ".noformat";
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+const WATCH_INTERVAL_SECS: u64 = 60;
+const GIT_RETRIES: u32 = 3;
+const CONTEXTS_DIR: &str = "contexts";
+/// How deep env file values referencing other values are expanded, so a cycle stops.
+const MAX_INTERPOLATION_DEPTH: usize = 8;
+/// Appended to a setting or variable name to read its value from the file it points at instead.
+const SECRET_FILE_SUFFIX: &str = "_FILE";
+/// Settings whose names end in `_FILE` because they are paths, not secrets to read.
+const PATH_SETTINGS: &[&str] = &["SERVER_SYNC_STATE_FILE", "SERVER_SYNC_VARS_FILE"];
+/// Settings that are only read from the env file or process env.
+const ENV_ONLY_SETTINGS: &[&str] = &["SERVER_SYNC_GIT_RETRIES", "UID", "USER", "GID", "GROUP"];
+
+/// Where the value of a setting was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    CommandArgs,
+    EnvFile,
+    ProcessEnv,
+    Default,
+    /// The file named by the setting with a `_FILE` suffix.
+    SecretFile,
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Origin::CommandArgs => "command args",
+            Origin::EnvFile => "env file",
+            Origin::ProcessEnv => "process env",
+            Origin::Default => "default",
+            Origin::SecretFile => "secret file",
+        })
+    }
+}
+
+/// A setting and the value it resolved to, `None` when it isn't set anywhere.
+pub struct Setting {
+    pub name: String,
+    pub value: Option<(String, Origin)>,
+}
 
 pub struct ServerContext {
     pub name: String,
     pub source_root: PathBuf,
+    /// The source roots of the contexts from `.syncinclude` layered underneath this one, the base first.
+    pub includes: Vec<PathBuf>,
+    /// Overrides the destination root for this context.
+    pub destination: Option<PathBuf>,
+    /// The root existing files are read from to merge with, instead of the destination they're written to.
+    pub merge_source: Option<PathBuf>,
+    pub encodings: BTreeMap<PathBuf, OutputEncoding>,
+    /// Variables produced from the rendered output of a file, in the order they are rendered.
+    pub exports: Vec<(String, PathBuf)>,
+    pub merge_drivers: Vec<(GlobMatcher, MergeDriver)>,
+    pub ignore: SyncIgnore,
+    /// Paths marked `export-ignore` in the repository's `.gitattributes` files.
+    pub export_ignore: SyncIgnore,
+    /// Files matching these rules are copied without templating.
+    pub raw_copy: SyncIgnore,
+    /// Variables from the context's `vars.toml`, these override the global variables for this context.
+    pub vars: Map<String, Value>,
+    /// The delimiters templates are written with instead of `{{ }}`, the last matching rule wins.
+    pub delimiters: Vec<(Option<GlobMatcher>, Delimiters)>,
 }
 
 impl ServerContext {
-    pub fn new(name: String, repo_path: &str) -> anyhow::Result<Self> {
-        let source_root = PathBuf::from(repo_path).join("contexts/").join(&name);
+    pub fn new(
+        name: String,
+        contexts_dir: &Path,
+        destination: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let source_root = contexts_dir.join(&name);
+        let destination = destination.map(PathBuf::from);
 
-        Ok(Self { name, source_root })
+        Ok(Self {
+            name,
+            source_root,
+            includes: vec![],
+            destination,
+            merge_source: None,
+            encodings: BTreeMap::new(),
+            exports: vec![],
+            merge_drivers: vec![],
+            ignore: SyncIgnore::default(),
+            export_ignore: SyncIgnore::default(),
+            raw_copy: SyncIgnore::default(),
+            vars: Map::new(),
+            delimiters: vec![],
+        })
+    }
+
+    /// Loads the manifests from the context, this has to happen after the repository is synced.
+    pub fn load_manifests(&mut self, repo_dir: &Path) -> anyhow::Result<()> {
+        let contexts_dir = self
+            .source_root
+            .ancestors()
+            .nth(Path::new(&self.name).components().count())
+            .context("Get contexts directory")?;
+        self.includes = includes::resolve(contexts_dir, &self.source_root)
+            .with_context(|| format!("Resolve includes of context {}", self.name))?;
+        self.encodings = load_manifest(&self.source_root).context("Load encoding manifest")?;
+        self.exports = read_manifest(&self.source_root, EXPORTS_MANIFEST)
+            .context("Load exports manifest")?
+            .into_iter()
+            .map(|(name, path)| (name, PathBuf::from(path)))
+            .collect();
+        self.merge_drivers =
+            crate::merger::load_manifest(&self.source_root).context("Load merge manifest")?;
+        self.ignore = SyncIgnore::load(&self.source_root).context("Load ignore rules")?;
+        self.raw_copy = SyncIgnore::load_file(&self.source_root, RAW_COPY_FILE)
+            .context("Load raw copy rules")?;
+        self.export_ignore = SyncIgnore::load_export_ignore(repo_dir, &self.source_root)
+            .context("Load gitattributes")?;
+
+        let vars_file = self.source_root.join(CONTEXT_VARS_FILE);
+        if vars_file.is_file() {
+            self.vars = read_vars_file(&vars_file).context("Load context vars")?;
+        }
+
+        Ok(())
+    }
+
+    /// The source roots files are layered from, the included contexts first and this context last.
+    pub fn layers(&self) -> impl DoubleEndedIterator<Item = &Path> {
+        self.includes
+            .iter()
+            .map(PathBuf::as_path)
+            .chain([self.source_root.as_path()])
+    }
+
+    /// Gets the path of a source file relative to the root of the layer it's from.
+    pub fn relative_path<'p>(&self, source: &'p Path) -> anyhow::Result<&'p Path> {
+        self.layers()
+            .rev()
+            .find_map(|root| source.strip_prefix(root).ok())
+            .with_context(|| format!("{} isn't in context {}", source.display(), self.name))
+    }
+
+    /// Gets the source of a relative path from the most specific layer that has it.
+    pub fn source_path(&self, relative_path: &Path) -> PathBuf {
+        self.layers()
+            .rev()
+            .map(|root| root.join(relative_path))
+            .find(|path| path.symlink_metadata().is_ok())
+            .unwrap_or_else(|| self.source_root.join(relative_path))
+    }
+
+    pub fn get_destination<'a>(&'a self, conf: &'a EnvConf) -> &'a Path {
+        self.destination
+            .as_deref()
+            .unwrap_or(&conf.destination_root)
+    }
+
+    /// Gets the existing file a destination file is merged with, which is the file itself
+    /// unless the context has a merge source.
+    pub fn merge_path(&self, conf: &EnvConf, destination_path: &Path) -> PathBuf {
+        let relative = destination_path.strip_prefix(self.get_destination(conf));
+        match (&self.merge_source, relative) {
+            (Some(merge_source), Ok(relative)) => merge_source.join(relative),
+            _ => destination_path.to_path_buf(),
+        }
+    }
+
+    /// Gets the merge driver of the last rule matching the path,
+    /// files without a rule fall back to the driver for their extension if it's one that's merged.
+    pub fn get_merge_driver(&self, relative_path: &Path, conf: &EnvConf) -> MergeDriver {
+        self.merge_drivers
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.is_match(relative_path))
+            .map(|(_, driver)| *driver)
+            .unwrap_or_else(|| match conf.merges_extension(relative_path) {
+                true => MergeDriver::for_extension(relative_path),
+                false => MergeDriver::TextOverwrite,
+            })
+    }
+
+    /// Checks if the path is one of the manifests that configure this context rather than a synced file.
+    pub fn is_manifest(&self, path: &Path) -> bool {
+        [
+            ENCODING_MANIFEST,
+            EXPORTS_MANIFEST,
+            MERGE_MANIFEST,
+            IGNORE_FILE,
+            RAW_COPY_FILE,
+            HOOK_FILE,
+            CONTEXT_VARS_FILE,
+            INCLUDE_FILE,
+        ]
+        .iter()
+        .any(|manifest| self.layers().any(|root| path == root.join(manifest)))
+            || path.file_name() == Some(NO_FORMAT_MARKER.as_ref())
+    }
+
+    /// Checks if a file is copied as is instead of being rendered, either because it matches
+    /// a `.rawcopy` rule or because it's in a directory marked with `.noformat`.
+    pub fn is_raw_copy(&self, relative_path: &Path) -> bool {
+        self.raw_copy.matches_within(relative_path)
+            || relative_path.ancestors().skip(1).any(|ancestor| {
+                self.layers()
+                    .any(|root| root.join(ancestor).join(NO_FORMAT_MARKER).exists())
+            })
+    }
+
+    /// Gets a template in handlebars syntax, templates written with other delimiters are rewritten.
+    pub fn template_source<'c>(&self, relative_path: &Path, contents: &'c str) -> Cow<'c, str> {
+        let delimiters = self
+            .delimiters
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.as_ref().is_none_or(|m| m.is_match(relative_path)));
+
+        match delimiters {
+            Some((_, delimiters)) => Cow::Owned(delimiters.translate(contents)),
+            None => Cow::Borrowed(contents),
+        }
+    }
+
+    pub fn get_encoding(&self, relative_path: &Path) -> OutputEncoding {
+        self.encodings
+            .get(relative_path)
+            .copied()
+            .unwrap_or_default()
     }
 }
 
@@ -36,17 +273,118 @@ pub struct EnvConf {
 
     pub contexts: Vec<ServerContext>,
 
+    /// The checked out repository, or the local source directory when there's no git repository.
+    pub repo_dir: PathBuf,
+
+    /// The directory of this repository under the repository storage, holding its clone and sync state
+    /// so several repositories can share the storage.
+    pub storage_dir: PathBuf,
+
+    /// Whether the source is a local directory that's used as it is without git.
+    pub local_source: bool,
+
+    /// The directory in the repository holding a directory for each context.
+    pub contexts_dir: PathBuf,
+
     pub destination_root: PathBuf,
+
+    /// The directory every destination is moved under, so a sync can be inspected before it goes live.
+    pub staging_root: Option<PathBuf>,
+
+    /// How files are backed up before they are replaced.
+    pub backup_mode: BackupMode,
+
+    /// The suffix appended to the file name of backups.
+    pub backup_suffix: String,
+
+    /// The most timestamped or numbered backups kept of each file, the oldest are removed past it.
+    pub max_backups: Option<usize>,
+
+    /// The minimum delay between file writes, if throttling is enabled.
+    pub write_delay: Option<Duration>,
+
+    /// The private key git authenticates to the repository with over SSH.
+    pub ssh_key: Option<PathBuf>,
+
+    /// The number of files processed in parallel, 0 uses one thread per CPU.
+    pub jobs: usize,
+
+    /// How often the repository is checked for new commits in watch mode.
+    pub watch_interval: Duration,
+
+    pub diff_format: DiffFormat,
+
+    /// Whether rendered JSON, YAML and TOML files are parsed before they're written.
+    pub validate: bool,
+
+    /// The extensions merged by default, every extension with a merge driver if unset.
+    pub merge_extensions: Option<Vec<String>>,
+
+    /// The line endings rendered files are normalized to.
+    pub line_ending: LineEnding,
+
+    /// How files are written to their destination.
+    pub backend: Backend,
+
+    /// How many times git operations that fail with a network error are retried.
+    pub git_retries: u32,
+
+    /// The mode for synced files without an override.
+    pub default_file_mode: u32,
+
+    /// The mode for directories created or fixed at the destination.
+    pub dir_mode: u32,
+
+    /// Modes for files matching a glob, the last matching rule wins.
+    pub mode_overrides: Vec<(GlobMatcher, u32)>,
+
+    /// Only files matching one of these globs are synced, every file when there are none.
+    pub include: Option<GlobSet>,
+
+    /// Files matching these globs aren't synced.
+    pub exclude: GlobSet,
+
+    /// The uid and gid given to synced files, ownership is left alone when this isn't set.
+    pub ownership: Option<(u32, u32)>,
+
+    pub permissions: Box<dyn PermissionManager>,
+
+    /// Structured variables from the vars file, the flat variables take precedence over them.
+    pub vars: Map<String, Value>,
+
+    /// Metadata of the synced commit like `git_commit`, every other variable takes precedence over them.
+    pub git_vars: Map<String, Value>,
+
+    /// Variables declared without a value that are read from their secret file instead.
+    secrets: BTreeMap<String, String>,
 }
 
 impl EnvConf {
     pub fn new(matches: ArgMatches) -> anyhow::Result<Self> {
-        let file = EnvFile::new(matches.get_one::<String>("SERVER_SYNC_ENV").unwrap()).ok();
+        let file = load_env_files(&matches);
+        // Listing contexts happens before there's anything to sync, so it doesn't need a destination or contexts.
+        let listing = _get_flag("SERVER_SYNC_LIST_CONTEXTS", &matches, &file);
         let raw_destination = _get_env("SERVER_SYNC_DESTINATION", &matches, &file)
+            .or_else(|| listing.then(String::new))
             .context("Get destination for sync")?;
 
-        let repo_path =
-            _get_env("SERVER_SYNC_REPO_STORAGE", &matches, &file).context("Get repository path")?;
+        let repo = _get_env("SERVER_SYNC_REPO", &matches, &file);
+        let storage_dir = _get_env("SERVER_SYNC_REPO_STORAGE", &matches, &file)
+            .map(|storage| {
+                PathBuf::from(storage).join(repo_storage_name(repo.as_deref().unwrap_or_default()))
+            })
+            .context("Get repository path")?;
+        let local_dir = repo.as_deref().and_then(local_source_dir);
+        let local_source = local_dir.is_some();
+        let repo_dir = local_dir.unwrap_or_else(|| storage_dir.clone());
+        let contexts_dir = repo_dir.join(
+            _get_env("SERVER_SYNC_CONTEXTS_DIR", &matches, &file)
+                .unwrap_or(CONTEXTS_DIR.to_string()),
+        );
+
+        let staging_root = _get_env("SERVER_SYNC_STAGING_ROOT", &matches, &file)
+            .map(|root| std::path::absolute(root).context("Resolve staging root"))
+            .transpose()?;
 
         let contexts = matches
             .get_many::<String>("SERVER_SYNC_CONTEXTS")
@@ -60,7 +398,13 @@ impl EnvConf {
             }))
             .map(|v| {
                 v.into_iter()
-                    .map(|s| ServerContext::new(s, &repo_path).unwrap())
+                    .map(|s| {
+                        let destination =
+                            _get_env(&format!("SERVER_SYNC_DEST_{}", s), &matches, &file).map(
+                                |destination| staged_path(staging_root.as_deref(), destination),
+                            );
+                        ServerContext::new(s, &contexts_dir, destination).unwrap()
+                    })
                     .collect::<Vec<_>>()
             })
             .unwrap_or_default();
@@ -68,36 +412,452 @@ impl EnvConf {
         debug!("Contexts: {:?}", contexts);
         debug!("Destination: {}", raw_destination);
 
-        let destination_root = PathBuf::from(raw_destination);
+        let destination_root = PathBuf::from(staged_path(staging_root.as_deref(), raw_destination));
 
-        if contexts.is_empty() {
+        let backup_suffix = _get_env("SERVER_SYNC_BACKUP_SUFFIX", &matches, &file)
+            .map(|suffix| suffix.trim_start_matches('.').to_string())
+            .filter(|suffix| !suffix.is_empty())
+            .unwrap_or("bak".to_string());
+
+        let backup_mode = _get_env("SERVER_SYNC_BACKUP_MODE", &matches, &file)
+            .map(|mode| mode.parse::<BackupMode>())
+            .transpose()?
+            .unwrap_or(BackupMode::Overwrite);
+
+        let max_backups = _get_env("SERVER_SYNC_MAX_BACKUPS", &matches, &file)
+            .map(|raw| {
+                raw.trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .with_context(|| {
+                        format!("Invalid max backups {}, expected a number above 0", raw)
+                    })
+            })
+            .transpose()?;
+
+        let diff_format = _get_env("SERVER_SYNC_DIFF_FORMAT", &matches, &file)
+            .map(|format| format.parse::<DiffFormat>())
+            .transpose()?
+            .unwrap_or(DiffFormat::Pretty);
+
+        let validate = _get_env("SERVER_SYNC_VALIDATE", &matches, &file)
+            .map(|validate| {
+                validate.trim().parse::<bool>().with_context(|| {
+                    format!(
+                        "Invalid SERVER_SYNC_VALIDATE {}, expected true or false",
+                        validate
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or(true);
+
+        let merge_extensions =
+            _get_env("SERVER_SYNC_MERGE_EXTENSIONS", &matches, &file).map(|extensions| {
+                extensions
+                    .split(',')
+                    .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|extension| !extension.is_empty())
+                    .collect()
+            });
+
+        let backend = _get_env("SERVER_SYNC_BACKEND", &matches, &file)
+            .map(|backend| backend.parse::<Backend>())
+            .transpose()?
+            .unwrap_or(Backend::Builtin);
+
+        let line_ending = _get_env("SERVER_SYNC_NORMALIZE_EOL", &matches, &file)
+            .map(|ending| ending.parse::<LineEnding>())
+            .transpose()?
+            .unwrap_or(LineEnding::Preserve);
+
+        let default_file_mode = _get_env("SERVER_SYNC_FILE_MODE", &matches, &file)
+            .map(|mode| parse_mode(&mode))
+            .transpose()?
+            .unwrap_or(FILE_MODE);
+
+        let dir_mode = _get_env("SERVER_SYNC_DIR_MODE", &matches, &file)
+            .map(|mode| parse_mode(&mode))
+            .transpose()?
+            .unwrap_or(DIR_MODE);
+
+        let permissions = native();
+        let ownership = match resolve_ownership(&matches, &file, permissions.as_ref()) {
+            Ok(ownership) => Some(ownership),
+            Err(err) if !_get_flag("SERVER_SYNC_REQUIRE_OWNERSHIP", &matches, &file) => {
+                warn!("Not changing the owner of synced files -> {:#}", err);
+                None
+            }
+            Err(err) => return Err(err.context("Resolve the owner for synced files")),
+        };
+
+        let mode_overrides = _get_env("SERVER_SYNC_MODES", &matches, &file)
+            .map(|raw| {
+                raw.split(';')
+                    .filter(|rule| !rule.trim().is_empty())
+                    .map(|rule| {
+                        let (glob, mode) = rule.split_once('=').with_context(|| {
+                            format!("Invalid mode rule {}, expected glob=mode", rule)
+                        })?;
+                        let matcher = Glob::new(glob.trim())
+                            .with_context(|| format!("Invalid mode glob {}", glob))?
+                            .compile_matcher();
+
+                        Ok((matcher, parse_mode(mode)?))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let include = _get_list("SERVER_SYNC_INCLUDE", &matches, &file);
+        let include = match include.is_empty() {
+            true => None,
+            false => Some(build_globs(&include).context("Compile include globs")?),
+        };
+        let exclude = build_globs(&_get_list("SERVER_SYNC_EXCLUDE", &matches, &file))
+            .context("Compile exclude globs")?;
+
+        let write_delay = match _get_env("SERVER_SYNC_THROTTLE", &matches, &file) {
+            None => None,
+            Some(raw) => {
+                let rate = raw
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|rate| rate.is_finite() && *rate > 0.0)
+                    .with_context(|| {
+                        format!("Invalid throttle {}, expected files per second", raw)
+                    })?;
+                Some(Duration::from_secs_f64(1.0 / rate))
+            }
+        };
+
+        let ssh_key = _get_env("SERVER_SYNC_SSH_KEY", &matches, &file).map(PathBuf::from);
+        if let Some(key) = &ssh_key {
+            // ssh would fall back to asking for a password and hang without a terminal.
+            if !key.is_file() {
+                return Err(format_err!("SSH key {} doesn't exist", key.display()));
+            }
+        }
+
+        let jobs = match _get_env("SERVER_SYNC_JOBS", &matches, &file) {
+            None => 0,
+            Some(raw) => raw
+                .parse::<usize>()
+                .ok()
+                .filter(|jobs| *jobs > 0)
+                .with_context(|| format!("Invalid jobs {}, expected a positive number", raw))?,
+        };
+
+        let watch_interval = match _get_env("SERVER_SYNC_WATCH_INTERVAL", &matches, &file) {
+            None => Duration::from_secs(WATCH_INTERVAL_SECS),
+            Some(raw) => raw
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .with_context(|| format!("Invalid watch interval {}, expected seconds", raw))?,
+        };
+
+        let git_retries = match _get_env("SERVER_SYNC_GIT_RETRIES", &matches, &file) {
+            None => GIT_RETRIES,
+            Some(raw) => raw
+                .parse::<u32>()
+                .with_context(|| format!("Invalid git retries {}, expected a number", raw))?,
+        };
+
+        // Throttling spaces out writes one after another, which parallel writes would defeat.
+        let jobs = match (write_delay, jobs) {
+            (Some(_), 0 | 1) => 1,
+            (Some(_), _) => {
+                return Err(format_err!(
+                    "SERVER_SYNC_THROTTLE can't be combined with more than one job"
+                ))
+            }
+            (None, jobs) => jobs,
+        };
+
+        if contexts.is_empty() && !listing {
             return Err(format_err!("No contexts to sync!"));
         }
 
+        // Read once here rather than for every rendered file.
+        let secrets = read_secret_variables(&read_variables(&file));
+
         Ok(Self {
             file,
             matches,
             contexts,
+            repo_dir,
+            storage_dir,
+            local_source,
+            contexts_dir,
             destination_root,
+            staging_root,
+            backup_mode,
+            max_backups,
+            backup_suffix,
+            write_delay,
+            ssh_key,
+            jobs,
+            watch_interval,
+            diff_format,
+            validate,
+            merge_extensions,
+            line_ending,
+            backend,
+            git_retries,
+            default_file_mode,
+            dir_mode,
+            mode_overrides,
+            include,
+            exclude,
+            ownership,
+            permissions,
+            vars: Map::new(),
+            git_vars: Map::new(),
+            secrets,
         })
     }
 
     pub fn get_env(&self, env: &str) -> Option<String> {
-        return _get_env(env, &self.matches, &self.file);
+        _get_env(env, &self.matches, &self.file)
+    }
+
+    /// Gets how many times `-v` was passed.
+    pub fn verbosity(&self) -> u8 {
+        self.matches.get_count("VERBOSE")
+    }
+
+    /// Checks if replaced and pruned files are backed up one by one, they aren't when backups
+    /// are disabled or each destination is snapshotted instead.
+    pub fn backs_up_files(&self) -> bool {
+        !self.get_flag("SERVER_SYNC_FORCE") && self.backup_mode != BackupMode::BtrfsSnapshot
+    }
+
+    pub fn get_flag(&self, flag: &str) -> bool {
+        _get_flag(flag, &self.matches, &self.file)
     }
 
     pub fn get_variables(&self) -> BTreeMap<String, String> {
-        let mut mut_map = if self.file.is_none() {
-            BTreeMap::new()
-        } else {
-            self.file.as_ref().unwrap().store.clone()
+        let mut variables = read_variables(&self.file);
+        variables.extend(self.secrets.clone());
+        variables
+    }
+
+    /// Expands contexts with a glob pattern as their name into every matching context directory,
+    /// this has to happen after the repository is synced.
+    pub fn expand_contexts(&mut self) -> anyhow::Result<()> {
+        let mut contexts: Vec<ServerContext> = vec![];
+
+        for context in std::mem::take(&mut self.contexts) {
+            if !context.name.contains(['*', '?', '[', '{']) {
+                if !contexts.iter().any(|c| c.name == context.name) {
+                    contexts.push(context);
+                }
+                continue;
+            }
+
+            let matcher = Glob::new(&context.name)
+                .with_context(|| format!("Invalid context pattern {}", context.name))?
+                .compile_matcher();
+            let mut names = read_dir(&self.contexts_dir)
+                .with_context(|| format!("Read {}", self.contexts_dir.display()))?
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| matcher.is_match(name))
+                .collect::<Vec<_>>();
+            names.sort();
+
+            if names.is_empty() {
+                warn!("Context pattern {} didn't match any contexts", context.name);
+            }
+
+            for name in names {
+                if contexts.iter().any(|c| c.name == name) {
+                    continue;
+                }
+
+                let destination = self
+                    .get_env(&format!("SERVER_SYNC_DEST_{}", name))
+                    .map(|destination| staged_path(self.staging_root.as_deref(), destination));
+                contexts.push(ServerContext::new(name, &self.contexts_dir, destination)?);
+            }
+        }
+
+        if contexts.is_empty() {
+            return Err(format_err!("No contexts to sync!"));
+        }
+
+        // Rules for a single context are added after the global ones so they take precedence.
+        let delimiters = self
+            .get_env("SERVER_SYNC_DELIMITERS")
+            .map(|raw| parse_rules(&raw).context("Parse SERVER_SYNC_DELIMITERS"))
+            .transpose()?
+            .unwrap_or_default();
+        for context in contexts.iter_mut() {
+            context.merge_source = self
+                .get_env(&format!("SERVER_SYNC_MERGE_SOURCE_{}", context.name))
+                .map(PathBuf::from);
+
+            let name = format!("SERVER_SYNC_DELIMITERS_{}", context.name);
+            context.delimiters = delimiters.clone();
+            if let Some(raw) = self.get_env(&name) {
+                context
+                    .delimiters
+                    .extend(parse_rules(&raw).with_context(|| format!("Parse {}", name))?);
+            }
+        }
+
+        debug!("Expanded contexts: {:?}", contexts);
+        self.contexts = contexts;
+        Ok(())
+    }
+
+    /// Gets where a path under the staging root goes once the staging root is moved into place,
+    /// paths are returned as they are without a staging root.
+    pub fn live_path(&self, path: &Path) -> PathBuf {
+        match self
+            .staging_root
+            .as_ref()
+            .and_then(|root| path.strip_prefix(root).ok())
+        {
+            Some(relative) => Path::new("/").join(relative),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Checks if a file relative to its context is selected by the include and exclude globs.
+    pub fn is_selected(&self, relative_path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative_path))
+            && !self.exclude.is_match(relative_path)
+    }
+
+    /// Checks the repository has the contexts directory, so a repository laid out differently
+    /// fails with the path that was expected instead of an error for every context.
+    pub fn check_contexts_dir(&self) -> anyhow::Result<()> {
+        match self.contexts_dir.is_dir() {
+            true => Ok(()),
+            false => Err(SyncError::ConfigError(format_err!(
+                "The contexts directory {} doesn't exist, set SERVER_SYNC_CONTEXTS_DIR if the repository keeps its contexts somewhere else",
+                self.contexts_dir.display()
+            ))
+            .into()),
+        }
+    }
+
+    /// Gets the mode for a synced file from the last matching override,
+    /// otherwise the source file's mode when preserving modes or the configured file mode.
+    pub fn file_mode(&self, source: &Path, relative_path: &Path) -> anyhow::Result<u32> {
+        if let Some((_, mode)) = self
+            .mode_overrides
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.is_match(relative_path))
+        {
+            return Ok(*mode);
+        }
+
+        if self.get_flag("SERVER_SYNC_PRESERVE_MODE") {
+            return self
+                .permissions
+                .mode(source)
+                .context("Read source file mode");
+        }
+
+        Ok(self.default_file_mode)
+    }
+
+    /// Gets the uid and gid a synced file is owned by, the owner or group from a template's front matter
+    /// replaces the configured one. Without a group the owner's id is used as the group id, like for UID.
+    pub fn file_ownership(
+        &self,
+        owner: Option<&str>,
+        group: Option<&str>,
+    ) -> anyhow::Result<Option<(u32, u32)>> {
+        let uid = owner
+            .map(|owner| match owner.parse() {
+                Ok(uid) => Ok(uid),
+                Err(_) => self.permissions.user_id(owner),
+            })
+            .transpose()?;
+        let gid = group
+            .map(|group| match group.parse() {
+                Ok(gid) => Ok(gid),
+                Err(_) => self.permissions.group_id(group),
+            })
+            .transpose()?;
+
+        match (uid, gid, self.ownership) {
+            (None, None, ownership) => Ok(ownership),
+            (Some(uid), gid, _) => Ok(Some((uid, gid.unwrap_or(uid)))),
+            (None, Some(gid), Some((uid, _))) => Ok(Some((uid, gid))),
+            (None, Some(group), None) => Err(format_err!(
+                "Can't set the group to {} without an owner, set owner in the front matter or UID",
+                group
+            )),
+        }
+    }
+
+    /// Loads the vars files, this has to happen after the repository is synced as they may live in it.
+    ///
+    /// Relative paths are resolved against the repository and each file is deep merged over the ones before it.
+    pub fn load_vars_file(&mut self) -> anyhow::Result<()> {
+        let raw = match self.get_env("SERVER_SYNC_VARS_FILE") {
+            None => return Ok(()),
+            Some(raw) => raw,
         };
 
-        std::env::vars().for_each(|(k, v)| {
-            mut_map.insert(k, v);
-        });
+        let mut vars = Map::new();
+        for path in vars_files(&self.repo_dir, &raw)? {
+            let file_vars =
+                read_vars_file(&path).with_context(|| format!("Load {}", path.display()))?;
+            merge_vars(&mut vars, file_vars, &path);
+        }
 
-        return mut_map;
+        self.vars = vars;
+        Ok(())
+    }
+
+    /// Checks if files with the extension of the path are merged when there's no merge rule for them.
+    pub fn merges_extension(&self, path: &Path) -> bool {
+        let extensions = match &self.merge_extensions {
+            None => return true,
+            Some(extensions) => extensions,
+        };
+
+        path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .is_some_and(|extension| extensions.contains(&extension))
+    }
+
+    /// Gets the data templates are rendered with, the flat variables are merged over the vars file.
+    pub fn get_template_data(&self) -> Map<String, Value> {
+        let mut data = self.git_vars.clone();
+        data.extend(self.vars.clone());
+        data.extend(
+            self.get_variables()
+                .into_iter()
+                .map(|(key, value)| (key, Value::String(value))),
+        );
+
+        data
+    }
+
+    /// Checks the owner for synced files can be resolved when one is configured.
+    pub fn check_ownership(&self) -> anyhow::Result<()> {
+        if ["UID", "USER", "GID", "GROUP"]
+            .iter()
+            .all(|key| self.get_env(key).is_none())
+        {
+            return Ok(());
+        }
+
+        resolve_ownership(&self.matches, &self.file, self.permissions.as_ref()).map(|_| ())
     }
 
     pub fn get_contexts(&self) -> &[ServerContext] {
@@ -105,31 +865,368 @@ impl EnvConf {
     }
 }
 
+/// Gets the name of a repository's directory under the repository storage from its url,
+/// like `github.com_DaRacci_ServerSync` for `https://github.com/DaRacci/ServerSync.git`.
+fn repo_storage_name(repo: &str) -> String {
+    let repo = repo.split_once("://").map_or(repo, |(_, rest)| rest);
+    // Drops the user of `git@host:path` and `ssh://user@host/path` urls.
+    let repo = match repo.split_once('@') {
+        Some((user, rest)) if !user.contains('/') => rest,
+        _ => repo,
+    };
+    let repo = repo.trim_end_matches('/');
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+
+    let name = repo
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+    let name = name.trim_matches(|c| c == '_' || c == '.');
+
+    match name.is_empty() {
+        true => "repository".to_string(),
+        false => name.to_string(),
+    }
+}
+
+/// Gets the directory of a local source, a `file://` repository or an existing directory
+/// that isn't a git repository is used as it is.
+fn local_source_dir(repo: &str) -> Option<PathBuf> {
+    if let Some(path) = repo.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+
+    let path = Path::new(repo);
+    let is_git = path.join(".git").exists()
+        || (path.join("HEAD").is_file() && path.join("objects").is_dir());
+    match path.is_dir() && !is_git {
+        true => Some(path.to_path_buf()),
+        false => None,
+    }
+}
+
+/// Moves a destination under the staging root, keeping its full absolute path below it.
+fn staged_path(staging_root: Option<&Path>, destination: String) -> String {
+    let root = match staging_root {
+        Some(root) => root,
+        None => return destination,
+    };
+
+    let destination = std::path::absolute(&destination).unwrap_or(PathBuf::from(destination));
+    let mut relative = PathBuf::new();
+    for component in destination.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            _ => {}
+        }
+    }
+    root.join(relative).to_string_lossy().to_string()
+}
+
+/// Loads every env file in order into one store, so keys in later files override earlier ones.
+/// Files that don't exist are skipped.
+fn load_env_files(matches: &ArgMatches) -> Option<EnvFile> {
+    let mut merged: Option<EnvFile> = None;
+    for path in matches
+        .get_many::<String>("SERVER_SYNC_ENV")
+        .into_iter()
+        .flatten()
+    {
+        let file = match EnvFile::new(path) {
+            Ok(file) => file,
+            Err(_) => {
+                debug!("Env file {} doesn't exist", path);
+                continue;
+            }
+        };
+
+        match &mut merged {
+            None => merged = Some(file),
+            Some(merged) => merged.store.extend(file.store),
+        }
+    }
+
+    merged
+}
+
+/// Resolves every setting the way a sync reads it along with where its value came from,
+/// the destination and hook of each context follow the named settings.
+pub fn resolve_settings(matches: &ArgMatches, names: &[&str]) -> Vec<Setting> {
+    let file = load_env_files(matches);
+    let resolve = |name: &str| {
+        // Settings without a command line option would make clap panic when asked for their source.
+        let source = matches
+            .try_contains_id(name)
+            .ok()
+            .and_then(|_| matches.value_source(name));
+        let value = match source {
+            Some(ValueSource::CommandLine) if matches.try_get_one::<bool>(name).is_ok() => {
+                Some((String::from("true"), Origin::CommandArgs))
+            }
+            Some(ValueSource::CommandLine | ValueSource::DefaultValue) => {
+                match matches.try_get_many::<String>(name) {
+                    Ok(Some(values)) => {
+                        let values = values.map(String::as_str).collect::<Vec<_>>();
+                        _resolve_env(name, matches, &file)
+                            .map(|(_, origin)| (values.join(";"), origin))
+                    }
+                    _ => _resolve_env(name, matches, &file),
+                }
+            }
+            _ => _resolve_env(name, matches, &file),
+        };
+
+        Setting {
+            name: name.to_string(),
+            value,
+        }
+    };
+
+    let mut settings = names
+        .iter()
+        .chain(ENV_ONLY_SETTINGS)
+        .map(|name| resolve(name))
+        .collect::<Vec<_>>();
+
+    let contexts = resolve("SERVER_SYNC_CONTEXTS")
+        .value
+        .map(|(contexts, _)| contexts)
+        .unwrap_or_default();
+    for context in contexts.split(';').filter(|context| !context.is_empty()) {
+        settings.push(resolve(&format!("SERVER_SYNC_DEST_{}", context)));
+        settings.push(resolve(&format!("SERVER_SYNC_HOOK_{}", context)));
+        settings.push(resolve(&format!("SERVER_SYNC_DELIMITERS_{}", context)));
+        settings.push(resolve(&format!("SERVER_SYNC_MERGE_SOURCE_{}", context)));
+    }
+
+    settings
+}
+
+/// Resolves the owner from UID or USER and the group from GID or GROUP,
+/// without a group the owner's id is used as the group id.
+fn resolve_ownership(
+    matches: &ArgMatches,
+    file: &Option<EnvFile>,
+    permissions: &dyn PermissionManager,
+) -> anyhow::Result<(u32, u32)> {
+    let uid = match (
+        _get_env("UID", matches, file),
+        _get_env("USER", matches, file),
+    ) {
+        (Some(uid), _) => uid
+            .parse()
+            .with_context(|| format!("Invalid UID {}", uid))?,
+        (None, Some(user)) => permissions.user_id(&user)?,
+        (None, None) => return Err(format_err!("Neither UID nor USER is set")),
+    };
+
+    let gid = match (
+        _get_env("GID", matches, file),
+        _get_env("GROUP", matches, file),
+    ) {
+        (Some(gid), _) => gid
+            .parse()
+            .with_context(|| format!("Invalid GID {}", gid))?,
+        (None, Some(group)) => permissions.group_id(&group)?,
+        (None, None) => uid,
+    };
+
+    Ok((uid, gid))
+}
+
+fn build_globs(globs: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob.trim()).with_context(|| format!("Invalid glob {}", glob))?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Parses an octal mode like `640` or `0o640`.
+pub fn parse_mode(raw: &str) -> anyhow::Result<u32> {
+    let trimmed = raw.trim();
+    u32::from_str_radix(trimmed.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .with_context(|| format!("Invalid mode {}, expected an octal mode like 644", trimmed))
+}
+
+/// Looks up a variable for interpolation, keys in the env file win over the process env.
+fn lookup_env(name: &str, file: &Option<EnvFile>, depth: usize) -> Option<String> {
+    if depth > MAX_INTERPOLATION_DEPTH {
+        warn!("Not expanding {} any further, it references itself", name);
+        return None;
+    }
+
+    match file.as_ref().and_then(|file| file.get(name)) {
+        Some(value) => Some(interpolate(value, &|name| {
+            lookup_env(name, file, depth + 1)
+        })),
+        None => std::env::var(name).ok(),
+    }
+}
+
+/// Gets the variables from the env file and process env, the process env wins.
+fn read_variables(file: &Option<EnvFile>) -> BTreeMap<String, String> {
+    let mut variables = match file {
+        None => BTreeMap::new(),
+        Some(envfile) => envfile
+            .store
+            .iter()
+            .map(|(key, value)| {
+                let lookup = |name: &str| lookup_env(name, file, 1);
+                (key.to_owned(), interpolate(value, &lookup))
+            })
+            .collect(),
+    };
+
+    variables.extend(std::env::vars());
+    variables
+}
+
+/// Reads the variables declared without a value from the file named by the same variable with a `_FILE` suffix.
+///
+/// Only declared variables are read so variables that happen to end in `_FILE`, like `LOG_FILE`, are left alone.
+fn read_secret_variables(variables: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    variables
+        .iter()
+        .filter(|(name, value)| value.is_empty() && has_secret_file(name))
+        .filter_map(|(name, _)| {
+            let path = variables.get(&secret_file_name(name))?;
+            Some((name.to_owned(), read_secret_file(path)?))
+        })
+        .collect()
+}
+
+fn secret_file_name(name: &str) -> String {
+    format!("{}{}", name, SECRET_FILE_SUFFIX)
+}
+
+/// Checks if a setting or variable can be read from a secret file, which settings that are paths themselves can't.
+fn has_secret_file(name: &str) -> bool {
+    !name.ends_with(SECRET_FILE_SUFFIX) && !PATH_SETTINGS.contains(&secret_file_name(name).as_str())
+}
+
+/// Gets a list from repeated command args, or a `;` separated value from the env file or process env.
+fn _get_list(env: &str, matches: &ArgMatches, file: &Option<EnvFile>) -> Vec<String> {
+    if let Ok(Some(values)) = matches.try_get_many::<String>(env) {
+        return values.cloned().collect();
+    }
+
+    _get_env(env, matches, file)
+        .map(|value| {
+            value
+                .split(';')
+                .filter(|value| !value.trim().is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn _get_flag(flag: &str, matches: &ArgMatches, file: &Option<EnvFile>) -> bool {
+    if let Ok(Some(&true)) = matches.try_get_one::<bool>(flag) {
+        return true;
+    }
+
+    _get_env(flag, matches, file)
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Gets a value from the command args, env file or process env in that order,
+/// `${NAME}` in values from the args or env file is expanded with [`lookup_env`].
 fn _get_env(env: &str, matches: &ArgMatches, file: &Option<EnvFile>) -> Option<String> {
-    if let Ok(env) = matches.try_get_one::<String>(env) {
-        if let Some(env) = env {
-            trace!("Found {} in command args", env);
-            return Some(env.to_string());
+    _resolve_env(env, matches, file).map(|(value, _)| value)
+}
+
+/// Gets a value like [`_get_env`] along with where it was found.
+///
+/// A setting that isn't set or is set without a value is read from the file named by the setting
+/// with a `_FILE` suffix, if there is one.
+fn _resolve_env(
+    env: &str,
+    matches: &ArgMatches,
+    file: &Option<EnvFile>,
+) -> Option<(String, Origin)> {
+    let value = _resolve_value(env, matches, file);
+    if value.as_ref().is_some_and(|(value, _)| !value.is_empty()) || !has_secret_file(env) {
+        return value;
+    }
+
+    match _resolve_value(&secret_file_name(env), matches, file) {
+        Some((path, _)) => {
+            trace!("Reading {} from {}", env, path);
+            read_secret_file(&path)
+                .map(|secret| (secret, Origin::SecretFile))
+                .or(value)
+        }
+        None => value,
+    }
+}
+
+fn _resolve_value(
+    env: &str,
+    matches: &ArgMatches,
+    file: &Option<EnvFile>,
+) -> Option<(String, Origin)> {
+    let lookup = |name: &str| lookup_env(name, file, 1);
+
+    if let Ok(Some(value)) = matches.try_get_one::<String>(env) {
+        match matches.value_source(env) {
+            // Values clap read from the process env were already set by a shell.
+            Some(ValueSource::EnvVariable) => {
+                trace!("Found {} in process env", value);
+                return Some((value.to_string(), Origin::ProcessEnv));
+            }
+            Some(ValueSource::DefaultValue) => {
+                trace!("Using default {}", value);
+                return Some((interpolate(value, &lookup), Origin::Default));
+            }
+            _ => {
+                trace!("Found {} in command args", value);
+                return Some((interpolate(value, &lookup), Origin::CommandArgs));
+            }
         }
     }
 
     if let Some(envfile) = file {
-        if let Some(env) = envfile.get(env) {
-            trace!("Found {} in env file", env);
-            return Some(env.to_string());
+        if let Some(value) = envfile.get(env) {
+            trace!("Found {} in env file", value);
+            return Some((interpolate(value, &lookup), Origin::EnvFile));
         }
     }
 
     if let Ok(env) = std::env::var(env) {
         trace!("Found {} in process env", env);
-        return Some(env);
+        return Some((env, Origin::ProcessEnv));
     }
 
     if let Some(env) = std::env::var_os(env) {
         trace!("Found {} in system env", env.to_string_lossy());
-        return Some(env.to_string_lossy().to_string());
+        return Some((env.to_string_lossy().to_string(), Origin::ProcessEnv));
     }
 
     trace!("Couldn't find {} in any env", env);
     None
 }
+
+/// Reads a secret mounted as a file, without the trailing newline most editors and tools add.
+fn read_secret_file(path: &str) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(value) => Some(value.trim().to_string()),
+        Err(err) => {
+            warn!("Failed to read secret file {} -> {}", path, err);
+            None
+        }
+    }
+}
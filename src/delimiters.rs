@@ -0,0 +1,115 @@
+use anyhow::{format_err, Context};
+use globset::{Glob, GlobMatcher};
+use std::str::FromStr;
+
+const HANDLEBARS_OPEN: &str = "{{";
+const HANDLEBARS_CLOSE: &str = "}}";
+
+/// The delimiters a template is written with in place of `{{` and `}}`,
+/// for files that are templated by another tool that uses them too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delimiters {
+    open: String,
+    close: String,
+}
+
+impl FromStr for Delimiters {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [open, close] if open != close => Ok(Delimiters {
+                open: open.to_string(),
+                close: close.to_string(),
+            }),
+            _ => Err(format_err!(
+                "Invalid delimiters {}, expected two different delimiters separated by a space like <% %>",
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl Delimiters {
+    /// Rewrites a template into handlebars syntax.
+    ///
+    /// Braces in the template are escaped so they're written as they are, braces that were already
+    /// escaped as `\{{` are left alone and a delimiter escaped with a backslash is written without it.
+    pub fn translate(&self, contents: &str) -> String {
+        let mut translated = String::with_capacity(contents.len());
+        let mut rest = contents;
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('\\') {
+                if let Some(after) = after.strip_prefix(self.open.as_str()) {
+                    translated.push_str(&self.open);
+                    rest = after;
+                    continue;
+                }
+
+                if let Some(after) = after.strip_prefix(HANDLEBARS_OPEN) {
+                    translated.push('\\');
+                    translated.push_str(HANDLEBARS_OPEN);
+                    rest = after;
+                    continue;
+                }
+            }
+
+            if let Some(after) = rest.strip_prefix(self.open.as_str()) {
+                translated.push_str(HANDLEBARS_OPEN);
+                match after.find(self.close.as_str()) {
+                    Some(end) => {
+                        translated.push_str(&after[..end]);
+                        translated.push_str(HANDLEBARS_CLOSE);
+                        rest = &after[end + self.close.len()..];
+                    }
+                    // Handlebars reports the unclosed expression.
+                    None => {
+                        translated.push_str(after);
+                        rest = "";
+                    }
+                }
+                continue;
+            }
+
+            if let Some(after) = rest.strip_prefix(HANDLEBARS_OPEN) {
+                translated.push('\\');
+                translated.push_str(HANDLEBARS_OPEN);
+                rest = after;
+                continue;
+            }
+
+            let next = rest.chars().next().unwrap();
+            translated.push(next);
+            rest = &rest[next.len_utf8()..];
+        }
+
+        translated
+    }
+}
+
+/// Parses `;` separated delimiter rules, each is either the delimiters for every file
+/// or a glob relative to the context root and the delimiters for the files it matches like `*.j2=<% %>`.
+pub fn parse_rules(raw: &str) -> anyhow::Result<Vec<(Option<GlobMatcher>, Delimiters)>> {
+    raw.split(';')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(|rule| {
+            // Delimiters can contain `=` themselves, like `<%= %>`, so a rule is only split when both sides make sense.
+            let globbed = rule
+                .split_once('=')
+                .filter(|(glob, _)| !glob.trim().is_empty() && !glob.trim().contains(' '))
+                .and_then(|(glob, delimiters)| Some((glob, delimiters.parse().ok()?)));
+
+            match globbed {
+                Some((glob, delimiters)) => {
+                    let matcher = Glob::new(glob.trim())
+                        .with_context(|| format!("Invalid delimiters glob {}", glob))?
+                        .compile_matcher();
+
+                    Ok((Some(matcher), delimiters))
+                }
+                None => Ok((None, rule.parse()?)),
+            }
+        })
+        .collect()
+}
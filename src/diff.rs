@@ -0,0 +1,77 @@
+use anyhow::format_err;
+use similar::{ChangeTag, TextDiff};
+use simplelog::info;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How the changes to a file are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// Coloured changed lines in the log.
+    Pretty,
+    /// A standard unified diff on stdout that can be fed to `patch`.
+    Unified,
+}
+
+impl FromStr for DiffFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "pretty" => Ok(DiffFormat::Pretty),
+            "unified" => Ok(DiffFormat::Unified),
+            other => Err(format_err!(
+                "Unknown diff format {}, expected pretty or unified",
+                other
+            )),
+        }
+    }
+}
+
+impl DiffFormat {
+    /// Prints the changes between the existing and rendered contents of the destination,
+    /// a destination that doesn't exist yet is diffed against `/dev/null`.
+    pub fn print<'a>(
+        &self,
+        diff: &'a TextDiff<'a, 'a, 'a, str>,
+        destination: &Path,
+        existed: bool,
+    ) {
+        match self {
+            DiffFormat::Pretty => {
+                let mut changes = vec![];
+                for change in diff.iter_all_changes() {
+                    let sign = match change.tag() {
+                        ChangeTag::Delete => "<red>-",
+                        ChangeTag::Insert => "<green>+",
+                        ChangeTag::Equal => continue,
+                    };
+
+                    changes.push(format!("{} {}", sign, change.to_string().trim()));
+                }
+
+                // Logged as one message so diffs of files synced in parallel don't interleave.
+                if !changes.is_empty() {
+                    info!("{}", changes.join("\n"));
+                }
+            }
+            DiffFormat::Unified => {
+                let destination = destination.display().to_string();
+                let old_header = match existed {
+                    true => destination.as_str(),
+                    false => "/dev/null",
+                };
+
+                let unified = diff
+                    .unified_diff()
+                    .header(old_header, &destination)
+                    .to_string();
+
+                // Written straight to stdout so the log prefix and colours don't end up in the patch.
+                if !unified.is_empty() {
+                    print!("{}", unified);
+                }
+            }
+        }
+    }
+}
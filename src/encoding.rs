@@ -0,0 +1,125 @@
+use crate::manifest::read_manifest;
+use anyhow::format_err;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+pub const ENCODING_MANIFEST: &str = ".server-sync-encoding";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct OutputEncoding {
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+impl Hash for OutputEncoding {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.encoding.name().hash(state);
+        self.bom.hash(state);
+    }
+}
+
+impl Default for OutputEncoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+impl Debug for OutputEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.encoding.name())?;
+        if self.bom {
+            write!(f, "+bom")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl OutputEncoding {
+    /// Parses an encoding label such as `utf-8`, `utf-8+bom` or `utf-16le+bom`.
+    pub fn parse(label: &str) -> anyhow::Result<Self> {
+        let label = label.trim().to_lowercase();
+        let (label, bom) = match label.strip_suffix("+bom") {
+            Some(label) => (label, true),
+            None => (label.as_str(), false),
+        };
+
+        let encoding = Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| format_err!("Unknown encoding {}", label))?;
+
+        if bom && ![UTF_8, UTF_16LE, UTF_16BE].contains(&encoding) {
+            return Err(format_err!(
+                "Encoding {} doesn't support a BOM",
+                encoding.name()
+            ));
+        }
+
+        Ok(Self { encoding, bom })
+    }
+
+    /// Encodes the contents of a file, failing when they have characters the encoding can't represent
+    /// rather than writing them as `&#NNNN;` like encoding_rs does.
+    pub fn encode(&self, path: &Path, contents: &str) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![];
+
+        if self.bom {
+            match self.encoding {
+                e if e == UTF_16LE => bytes.extend_from_slice(&[0xFF, 0xFE]),
+                e if e == UTF_16BE => bytes.extend_from_slice(&[0xFE, 0xFF]),
+                _ => bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]),
+            }
+        }
+
+        // encoding_rs only decodes UTF-16, so it has to be encoded by hand.
+        match self.encoding {
+            e if e == UTF_16LE => contents
+                .encode_utf16()
+                .for_each(|unit| bytes.extend_from_slice(&unit.to_le_bytes())),
+            e if e == UTF_16BE => contents
+                .encode_utf16()
+                .for_each(|unit| bytes.extend_from_slice(&unit.to_be_bytes())),
+            _ => {
+                let (encoded, _, unmappable) = self.encoding.encode(contents);
+                if unmappable {
+                    return Err(format_err!(
+                        "{} has characters that {} can't represent",
+                        path.display(),
+                        self.encoding.name()
+                    ));
+                }
+                bytes.extend_from_slice(&encoded);
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        let (decoded, _, had_errors) = self.encoding.decode(bytes);
+        if had_errors {
+            return None;
+        }
+
+        Some(decoded.into_owned())
+    }
+}
+
+/// Loads the encoding manifest from the root of a context if it exists.
+///
+/// Each line maps a path relative to the context root to an encoding label,
+/// e.g. `config/legacy.ini=utf-16le+bom`.
+pub fn load_manifest(source_root: &Path) -> anyhow::Result<BTreeMap<PathBuf, OutputEncoding>> {
+    let mut encodings = BTreeMap::new();
+
+    for (path, label) in read_manifest(source_root, ENCODING_MANIFEST)? {
+        encodings.insert(PathBuf::from(path), OutputEncoding::parse(&label)?);
+    }
+
+    Ok(encodings)
+}
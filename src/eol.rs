@@ -0,0 +1,49 @@
+use anyhow::format_err;
+use std::str::FromStr;
+
+/// The line endings rendered files are written with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// Keeps whatever line endings the template and merged contents have.
+    Preserve,
+}
+
+impl FromStr for LineEnding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            "preserve" => Ok(LineEnding::Preserve),
+            other => Err(format_err!(
+                "Unknown line ending {}, expected lf, crlf or preserve",
+                other
+            )),
+        }
+    }
+}
+
+impl LineEnding {
+    /// Converts every line ending in the contents, optionally making sure a non empty file ends with one.
+    pub fn normalize(&self, contents: &str, trailing_newline: bool) -> String {
+        let mut normalized = match self {
+            LineEnding::Lf => contents.replace("\r\n", "\n"),
+            LineEnding::Crlf => contents.replace("\r\n", "\n").replace('\n', "\r\n"),
+            LineEnding::Preserve => contents.to_owned(),
+        };
+
+        if trailing_newline && !normalized.is_empty() && !normalized.ends_with('\n') {
+            let newline = match self {
+                LineEnding::Crlf => "\r\n",
+                LineEnding::Preserve if normalized.contains("\r\n") => "\r\n",
+                _ => "\n",
+            };
+            normalized.push_str(newline);
+        }
+
+        normalized
+    }
+}
@@ -0,0 +1,88 @@
+use handlebars::RenderError;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// The ways a sync can fail that callers may want to handle differently,
+/// each exits with its own code so scripts can retry or alert on specific kinds.
+#[derive(Debug)]
+pub enum SyncError {
+    /// A git command failed, with what it printed to stderr.
+    GitFailure { action: String, stderr: String },
+    /// A template couldn't be rendered.
+    RenderFailure { file: PathBuf, source: RenderError },
+    /// A rendered file couldn't be merged with the file at its destination.
+    MergeFailure {
+        file: PathBuf,
+        source: anyhow::Error,
+    },
+    /// The mode or owner of a synced path couldn't be set.
+    PermissionFailure {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// A file couldn't be read or written.
+    IoFailure {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The configuration is missing something or doesn't make sense.
+    ConfigError(anyhow::Error),
+    /// Another sync holds the lock on the repository storage.
+    Locked(PathBuf),
+}
+
+impl SyncError {
+    /// Finds the kind of failure behind an error, if it was one callers can tell apart.
+    pub fn find(err: &anyhow::Error) -> Option<&SyncError> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<SyncError>())
+    }
+
+    /// Gets the code the process exits with for this failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SyncError::ConfigError(_) => 19,
+            SyncError::GitFailure { .. } => 20,
+            SyncError::RenderFailure { .. } => 21,
+            SyncError::MergeFailure { .. } => 22,
+            SyncError::PermissionFailure { .. } => 23,
+            SyncError::IoFailure { .. } => 24,
+            SyncError::Locked(_) => 25,
+        }
+    }
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::GitFailure { action, stderr } => write!(f, "{} -> {}", action, stderr),
+            SyncError::RenderFailure { file, .. } => {
+                write!(f, "Render template {}", file.display())
+            }
+            SyncError::MergeFailure { file, .. } => write!(f, "Merge into {}", file.display()),
+            SyncError::PermissionFailure { path, .. } => {
+                write!(f, "Set permissions of {}", path.display())
+            }
+            SyncError::IoFailure { path, .. } => write!(f, "Read {}", path.display()),
+            SyncError::ConfigError(err) => write!(f, "{}", err),
+            SyncError::Locked(path) => write!(
+                f,
+                "Another sync is running and holds {}, pass --wait to wait for it to finish",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::GitFailure { .. } | SyncError::Locked(_) => None,
+            SyncError::RenderFailure { source, .. } => Some(source),
+            SyncError::MergeFailure { source, .. }
+            | SyncError::PermissionFailure { source, .. } => Some(source.as_ref()),
+            SyncError::IoFailure { source, .. } => Some(source),
+            SyncError::ConfigError(err) => err.source(),
+        }
+    }
+}
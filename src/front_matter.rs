@@ -0,0 +1,107 @@
+use crate::config::parse_mode;
+use crate::merger::MergeDriver;
+use anyhow::{format_err, Context};
+use serde_json::{Map, Value};
+use std::path::{Component, Path};
+
+const DELIMITER: &str = "---";
+const KEYS: &[&str] = &["mode", "owner", "group", "merge", "destination", "skip_if"];
+
+/// Directives for a single template from a `---` YAML block at its top, which isn't rendered.
+///
+/// A block is only taken as front matter when every key in it is one of these, so a YAML file
+/// starting with a document marker is rendered as it is.
+#[derive(Debug, Default)]
+pub struct FrontMatter {
+    /// The mode of the synced file, instead of the one from `SERVER_SYNC_MODES` or the default.
+    pub mode: Option<u32>,
+    /// The user owning the synced file, by name or id.
+    pub owner: Option<String>,
+    /// The group owning the synced file, by name or id.
+    pub group: Option<String>,
+    /// The merge driver, instead of the one from the merge manifest or the file's extension.
+    pub merge: Option<MergeDriver>,
+    /// The path the file is synced to relative to the destination, rendered like a templated path.
+    pub destination: Option<String>,
+    /// A condition like `server-sync-if` takes, the file is skipped when it's true.
+    pub skip_if: Option<String>,
+}
+
+impl FrontMatter {
+    /// Splits the front matter off a template, a template without any is returned as it is.
+    pub fn split(contents: &str) -> anyhow::Result<(FrontMatter, &str)> {
+        let (yaml, body) = match find_block(contents) {
+            Some(block) => block,
+            None => return Ok((FrontMatter::default(), contents)),
+        };
+
+        let directives = match serde_yaml::from_str::<Value>(yaml) {
+            Ok(Value::Object(directives))
+                if !directives.is_empty()
+                    && directives.keys().all(|key| KEYS.contains(&key.as_str())) =>
+            {
+                directives
+            }
+            _ => return Ok((FrontMatter::default(), contents)),
+        };
+
+        Ok((parse(&directives)?, body))
+    }
+}
+
+/// Finds the YAML between a `---` first line and the next `---` line, and the contents after it.
+fn find_block(contents: &str) -> Option<(&str, &str)> {
+    let rest = contents.strip_prefix(DELIMITER)?;
+    let rest = rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == DELIMITER {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+
+    None
+}
+
+fn parse(directives: &Map<String, Value>) -> anyhow::Result<FrontMatter> {
+    let destination = scalar(directives, "destination")?;
+    if let Some(destination) = &destination {
+        let stays_inside = Path::new(destination)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if destination.is_empty() || !stays_inside {
+            return Err(format_err!(
+                "Front matter destination {} has to be a relative path inside the destination",
+                destination
+            ));
+        }
+    }
+
+    Ok(FrontMatter {
+        // A mode written as a number like 644 is read as the octal digits it was written with.
+        mode: scalar(directives, "mode")?
+            .map(|mode| parse_mode(&mode))
+            .transpose()?,
+        owner: scalar(directives, "owner")?,
+        group: scalar(directives, "group")?,
+        merge: scalar(directives, "merge")?
+            .map(|merge| merge.parse())
+            .transpose()
+            .context("Front matter merge")?,
+        destination,
+        skip_if: scalar(directives, "skip_if")?,
+    })
+}
+
+fn scalar(directives: &Map<String, Value>, key: &str) -> anyhow::Result<Option<String>> {
+    match directives.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.to_owned())),
+        Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(Some(value.to_string())),
+        Some(_) => Err(format_err!("Front matter {} has to be a single value", key)),
+    }
+}
@@ -0,0 +1,162 @@
+use crate::conditional::CONDITION_HELPER;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, PathAndJson, RenderContext, RenderError,
+    Renderable,
+};
+use serde_json::Value;
+
+/// Registers the helpers available to every template.
+pub fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("upper", Box::new(upper));
+    handlebars.register_helper("lower", Box::new(lower));
+    handlebars.register_helper("default", Box::new(default));
+    handlebars.register_helper("env", Box::new(env));
+    handlebars.register_helper("b64", Box::new(b64));
+    handlebars.register_helper(CONDITION_HELPER, Box::new(server_sync_if));
+}
+
+/// `{{upper value}}` uppercases the value.
+fn upper(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = param_string(&params(h, 1)?[0], r)?;
+    out.write(&value.to_uppercase())?;
+    Ok(())
+}
+
+/// `{{lower value}}` lowercases the value.
+fn lower(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = param_string(&params(h, 1)?[0], r)?;
+    out.write(&value.to_lowercase())?;
+    Ok(())
+}
+
+/// `{{default value "fallback"}}` uses the fallback when the value is missing, null or empty,
+/// this is allowed in strict mode.
+fn default(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = params(h, 2)?;
+    let value = match params[0].is_value_missing() {
+        true => String::new(),
+        false => params[0].render(),
+    };
+
+    match value.is_empty() {
+        true => out.write(&param_string(&params[1], r)?)?,
+        false => out.write(&value)?,
+    }
+
+    Ok(())
+}
+
+/// `{{env "NAME"}}` reads a variable from the process environment.
+fn env(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let name = param_string(&params(h, 1)?[0], r)?;
+    match std::env::var(&name) {
+        Ok(value) => out.write(&value)?,
+        Err(_) if r.strict_mode() => {
+            return Err(RenderError::new(format!(
+                "Environment variable {} isn't set",
+                name
+            )))
+        }
+        Err(_) => {}
+    }
+
+    Ok(())
+}
+
+/// `{{b64 value}}` base64 encodes the value.
+fn b64(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = param_string(&params(h, 1)?[0], r)?;
+    out.write(&STANDARD.encode(value))?;
+    Ok(())
+}
+
+/// `{{#server-sync-if condition}}...{{/server-sync-if}}` renders its block when the condition is truthy,
+/// a missing value is false even in strict mode. Wrapping a whole file in it skips the file when it's false.
+fn server_sync_if<'reg, 'rc>(
+    h: &Helper<'reg, 'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let condition = &params(h, 1)?[0];
+    let template = match !condition.is_value_missing() && is_truthy(condition.value()) {
+        true => h.template(),
+        false => h.inverse(),
+    };
+
+    match template {
+        Some(template) => template.render(r, ctx, rc, out),
+        None => Ok(()),
+    }
+}
+
+/// Checks a value the way the built-in `if` does, so zero, empty strings and empty arrays are false.
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64().is_some_and(|number| number != 0.0),
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+/// Gets the parameters of a helper, failing unless it was given exactly as many as it takes.
+fn params<'h, 'reg, 'rc>(
+    h: &'h Helper<'reg, 'rc>,
+    arity: usize,
+) -> Result<&'h [PathAndJson<'reg, 'rc>], RenderError> {
+    if h.params().len() != arity {
+        return Err(RenderError::new(format!(
+            "Helper {} takes {} parameter(s) but was given {}",
+            h.name(),
+            arity,
+            h.params().len()
+        )));
+    }
+
+    Ok(h.params())
+}
+
+/// Renders a parameter as a string, a missing value is an error in strict mode.
+fn param_string(param: &PathAndJson, r: &Handlebars) -> Result<String, RenderError> {
+    if param.is_value_missing() && r.strict_mode() {
+        return Err(RenderError::strict_error(param.relative_path()));
+    }
+
+    Ok(param.render())
+}
@@ -0,0 +1,101 @@
+use anyhow::format_err;
+use hocon::{Hocon, HoconLoader};
+use std::fmt::Write;
+
+/// Parses a HOCON document without resolving environment variables, so they aren't baked into the output.
+///
+/// Substitutions that can't be resolved within the document are an error,
+/// as writing them back would lose them.
+pub fn parse(contents: &str) -> anyhow::Result<Hocon> {
+    let document = HoconLoader::new().no_system().load_str(contents)?.hocon()?;
+
+    check_values(&document)?;
+    match document {
+        Hocon::Hash(_) => Ok(document),
+        _ => Err(format_err!("Expected the document to be a map")),
+    }
+}
+
+fn check_values(value: &Hocon) -> anyhow::Result<()> {
+    match value {
+        Hocon::BadValue(err) => Err(format_err!("Invalid value: {}", err)),
+        Hocon::Array(values) => values.iter().try_for_each(check_values),
+        Hocon::Hash(values) => values.values().try_for_each(check_values),
+        _ => Ok(()),
+    }
+}
+
+/// Writes a document as HOCON with one key per line and nested objects in braces.
+pub fn to_string(document: &Hocon) -> String {
+    let mut output = String::new();
+    if let Hocon::Hash(values) = document {
+        for (key, value) in values {
+            write_entry(&mut output, key, value, 0);
+        }
+    }
+
+    output
+}
+
+fn write_entry(output: &mut String, key: &str, value: &Hocon, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match value {
+        Hocon::Hash(values) => {
+            let _ = writeln!(output, "{}{} {{", indent, format_key(key));
+            for (key, value) in values {
+                write_entry(output, key, value, depth + 1);
+            }
+            let _ = writeln!(output, "{}}}", indent);
+        }
+        value => {
+            let _ = writeln!(
+                output,
+                "{}{} = {}",
+                indent,
+                format_key(key),
+                format_value(value, depth)
+            );
+        }
+    }
+}
+
+fn format_value(value: &Hocon, depth: usize) -> String {
+    match value {
+        Hocon::Real(value) => format!("{:?}", value),
+        Hocon::Integer(value) => value.to_string(),
+        Hocon::String(value) => quote(value),
+        Hocon::Boolean(value) => value.to_string(),
+        Hocon::Null | Hocon::BadValue(_) => "null".to_string(),
+        Hocon::Array(values) => {
+            let values = values
+                .iter()
+                .map(|value| format_value(value, depth))
+                .collect::<Vec<_>>();
+            format!("[{}]", values.join(", "))
+        }
+        Hocon::Hash(values) => {
+            let mut nested = String::new();
+            for (key, value) in values {
+                write_entry(&mut nested, key, value, depth + 1);
+            }
+            format!("{{\n{}{}}}", nested, "  ".repeat(depth))
+        }
+    }
+}
+
+/// Keys with anything other than letters, digits, dashes and underscores are quoted,
+/// a bare dot would otherwise make a path.
+fn format_key(key: &str) -> String {
+    match !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        true => key.to_string(),
+        false => quote(key),
+    }
+}
+
+fn quote(value: &str) -> String {
+    serde_json::Value::String(value.to_string()).to_string()
+}
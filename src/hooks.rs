@@ -0,0 +1,91 @@
+use crate::config::{EnvConf, ServerContext};
+use anyhow::{format_err, Context};
+use simplelog::{info, trace};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub const HOOK_FILE: &str = ".synchook";
+
+/// Runs the post sync hook of a context, if it has one.
+///
+/// `SERVER_SYNC_HOOK_<context>` is run as a shell command and takes precedence over
+/// a `.synchook` executable in the context root. The changed paths relative to the
+/// destination are passed one per line on stdin and in `SERVER_SYNC_CHANGED_FILES`.
+pub fn run_post_sync(
+    context: &ServerContext,
+    conf: &EnvConf,
+    changed: &[PathBuf],
+) -> anyhow::Result<()> {
+    let hook_file = context.source_root.join(HOOK_FILE);
+    let mut cmd = match conf.get_env(&format!("SERVER_SYNC_HOOK_{}", context.name)) {
+        Some(command) => shell(&command),
+        None if hook_file.is_file() => Command::new(&hook_file),
+        None => return Ok(()),
+    };
+
+    let changed = changed
+        .iter()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    info!("Running post sync hook for context {}", context.name);
+    cmd.current_dir(context.get_destination(conf))
+        .env("SERVER_SYNC_CONTEXT", &context.name)
+        .env("SERVER_SYNC_CHANGED_FILES", &changed);
+
+    run(&mut cmd, &changed)
+}
+
+/// Runs the pre sync hook against the staged output of every context.
+///
+/// The staging directory has a folder per context and is passed as the first argument
+/// and in `SERVER_SYNC_STAGING`, a hook that exits non-zero aborts the sync.
+pub fn run_pre_sync(command: &str, staging: &Path) -> anyhow::Result<()> {
+    info!("Running pre sync hook");
+
+    let mut cmd = shell(command);
+    cmd.arg("server-sync-pre-hook")
+        .arg(staging)
+        .env("SERVER_SYNC_STAGING", staging);
+
+    run(&mut cmd, "")
+}
+
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Runs a hook with the input on stdin, logging its output and failing if it exits non-zero.
+fn run(cmd: &mut Command, input: &str) -> anyhow::Result<()> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Start hook")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook may exit without reading its input, which isn't an error.
+        let _ = stdin.write_all(input.as_bytes());
+    }
+
+    let output = child.wait_with_output().context("Wait for hook")?;
+    trace!(
+        "Hook output -> <blue>{}",
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "Hook exited with {} -> {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use crate::error::SyncError;
+use anyhow::{format_err, Context};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+pub const INCLUDE_FILE: &str = ".syncinclude";
+
+/// Resolves the contexts layered underneath a context from its `.syncinclude`, and theirs in turn,
+/// returning their source roots with the base first so each later one overrides the files before it.
+///
+/// A context included by several others is only layered once, where it's first included.
+pub fn resolve(contexts_dir: &Path, source_root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut layers = vec![];
+    visit(contexts_dir, source_root, &mut vec![], &mut layers)?;
+
+    // The context itself is always layered last.
+    layers.pop();
+    Ok(layers)
+}
+
+fn visit(
+    contexts_dir: &Path,
+    source_root: &Path,
+    chain: &mut Vec<PathBuf>,
+    layers: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    if chain.iter().any(|root| root == source_root) {
+        let names = chain
+            .iter()
+            .skip_while(|root| *root != source_root)
+            .chain([&source_root.to_path_buf()])
+            .map(|root| context_name(contexts_dir, root))
+            .collect::<Vec<_>>();
+
+        return Err(
+            SyncError::ConfigError(format_err!("Include cycle {}", names.join(" -> "))).into(),
+        );
+    }
+
+    if layers.iter().any(|root| root == source_root) {
+        return Ok(());
+    }
+
+    chain.push(source_root.to_path_buf());
+    for name in read_includes(source_root)? {
+        let included = contexts_dir.join(&name);
+        if !included.is_dir() {
+            return Err(SyncError::ConfigError(format_err!(
+                "Context {} includes {} which doesn't exist",
+                context_name(contexts_dir, source_root),
+                name
+            ))
+            .into());
+        }
+
+        visit(contexts_dir, &included, chain, layers)?;
+    }
+    chain.pop();
+
+    layers.push(source_root.to_path_buf());
+    Ok(())
+}
+
+/// Reads the context names listed one per line, skipping blank lines and `#` comments.
+fn read_includes(source_root: &Path) -> anyhow::Result<Vec<String>> {
+    let path = source_root.join(INCLUDE_FILE);
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    Ok(read_to_string(&path)
+        .with_context(|| format!("Read {}", path.display()))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn context_name(contexts_dir: &Path, source_root: &Path) -> String {
+    source_root
+        .strip_prefix(contexts_dir)
+        .unwrap_or(source_root)
+        .display()
+        .to_string()
+}
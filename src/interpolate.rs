@@ -0,0 +1,48 @@
+/// Expands `${NAME}` and `$NAME` in a value with the lookup, `$$` is a literal dollar.
+///
+/// Names that aren't set expand to nothing like they would in a shell,
+/// a `$` that doesn't start a name or an unclosed `${` is kept as is.
+pub fn interpolate(value: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some((name, after)) = rest
+            .strip_prefix('{')
+            .and_then(|braced| braced.split_once('}'))
+            .filter(|(name, _)| is_name(name))
+        {
+            expanded.push_str(&lookup(name).unwrap_or_default());
+            rest = after;
+        } else {
+            let length = rest
+                .char_indices()
+                .find(|(i, c)| {
+                    !(c.is_ascii_alphanumeric() || *c == '_') || (*i == 0 && c.is_ascii_digit())
+                })
+                .map_or(rest.len(), |(i, _)| i);
+
+            match length {
+                0 => expanded.push('$'),
+                _ => expanded.push_str(&lookup(&rest[..length]).unwrap_or_default()),
+            }
+            rest = &rest[length..];
+        }
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+fn is_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,93 @@
+use anyhow::format_err;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::io::Write;
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How log records are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Coloured messages for a terminal.
+    Pretty,
+    /// One JSON object per line for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format_err!(
+                "Unknown log format {}, expected pretty or json",
+                other
+            )),
+        }
+    }
+}
+
+/// Writes every record as a JSON line, errors go to stderr and everything else to stdout
+/// the same as the terminal logger.
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    pub fn init(level: LevelFilter) -> anyhow::Result<()> {
+        log::set_boxed_logger(Box::new(JsonLogger { level }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let line = json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": strip_colours(&record.args().to_string()),
+        });
+
+        // A closed pipe can't be logged to, so there's nothing to do with the error.
+        let _ = match record.level() {
+            Level::Error => writeln!(std::io::stderr().lock(), "{}", line),
+            _ => writeln!(std::io::stdout().lock(), "{}", line),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Removes the ANSI colour codes the log macros add for `<red>` style tags.
+pub fn strip_colours(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
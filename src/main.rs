@@ -1,59 +1,218 @@
+mod backup;
+mod conditional;
 mod config;
-
-use crate::config::{EnvConf, ServerContext};
+mod delimiters;
+mod diff;
+mod encoding;
+mod eol;
+mod error;
+mod front_matter;
+mod helpers;
+mod hocon_format;
+mod hooks;
+mod includes;
+mod interpolate;
+mod json_log;
+mod manifest;
+mod merger;
+mod output;
+mod permissions;
+mod plain_log;
+mod progress;
+mod render_trace;
+mod report;
+mod rsync;
+mod state;
+mod symlinks;
+mod sync_ignore;
+mod vars;
+mod webhook;
+
+use crate::backup::BackupMode;
+use crate::conditional::{condition_template, Conditional, CONDITION_HELPER};
+use crate::config::{EnvConf, Origin, ServerContext, Setting};
+use crate::encoding::OutputEncoding;
+use crate::error::SyncError;
+use crate::front_matter::FrontMatter;
+use crate::json_log::{JsonLogger, LogFormat};
+use crate::merger::{DocumentError, MergeDriver};
+use crate::output::OutputFormat;
+use crate::plain_log::PlainLogger;
+use crate::report::{FileChange, SyncReport};
+use crate::state::{ContextState, FileCache, SyncState, STATE_FILE};
+use crate::sync_ignore::{GIT_ATTRIBUTES_FILE, RAW_COPY_FILE};
 use anyhow::{format_err, Context};
 use clap::{command, Arg, ArgAction, ArgMatches};
-use file_owner::{group, owner};
-use handlebars::Handlebars;
-use similar::{ChangeTag, DiffableStr, TextDiff};
-use simplelog::__private::log::SetLoggerError;
+use handlebars::{Handlebars, RenderError};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde_json::{json, Map, Value};
+use similar::TextDiff;
 use simplelog::{
-    debug, error, info, trace, Color, ColorChoice, Config, ConfigBuilder, LevelFilter, TermLogger,
+    debug, error, info, trace, warn, ColorChoice, ConfigBuilder, LevelFilter, TermLogger,
     TerminalMode,
 };
-use std::env::{current_dir, vars_os};
-use std::error::Error;
-use std::fs::{create_dir, create_dir_all, read, rename, set_permissions, File, Permissions};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::{
+    copy, create_dir, create_dir_all, read, read_dir, read_link, remove_dir_all, remove_file,
+    rename, symlink_metadata, write, File, TryLockError,
+};
+use std::hash::{Hash, Hasher};
+use std::io::{copy as copy_stream, BufRead, BufReader, ErrorKind, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process::{exit, Command};
-use std::{env, fs};
-use walkdir::{DirEntry, WalkDir};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// Set for ssh when it runs this binary as its askpass program, see [configure_ssh].
+const ASKPASS_ENV: &str = "SERVER_SYNC_ASKPASS";
+
+/// The directory next to `contexts/` holding partials shared by every template.
+const PARTIALS_DIR: &str = "partials/";
+/// The buffer size used when comparing and copying files that aren't templated.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+/// How often watch mode checks for an interrupt while waiting.
+const WATCH_POLL: Duration = Duration::from_millis(200);
+/// The wait before the first retry of a git operation, doubled for every retry after it.
+const GIT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Parts of git errors caused by the network rather than the repository or credentials.
+const TRANSIENT_GIT_ERRORS: &[&str] = &[
+    "could not resolve host",
+    "connection timed out",
+    "connection refused",
+    "connection reset",
+    "failed to connect",
+    "operation timed out",
+    "the remote end hung up unexpectedly",
+    "early eof",
+    "rpc failed",
+    "temporary failure in name resolution",
+    "network is unreachable",
+];
+/// Extensions of text files that are expected to be templates, so strict utf8 fails when they can't be read as text.
+const TEMPLATE_EXTENSIONS: &[&str] = &[
+    "cfg",
+    "conf",
+    "env",
+    "hocon",
+    "html",
+    "ini",
+    "j2",
+    "js",
+    "json",
+    "mcmeta",
+    "md",
+    "properties",
+    "service",
+    "sh",
+    "toml",
+    "txt",
+    "xml",
+    "yaml",
+    "yml",
+];
 
 fn main() {
+    if let Some(passphrase) = askpass_passphrase() {
+        println!("{}", passphrase);
+        exit(0);
+    }
+
     let cli = get_cli();
     start_logger(&cli).context("Init logger").unwrap();
-    let conf = match EnvConf::new(cli) {
+    if cli.get_flag("SERVER_SYNC_PRINT_CONFIG") {
+        match print_config(&cli) {
+            Ok(_) => exit(0),
+            Err(err) => {
+                error!("{}", err);
+                exit(1);
+            }
+        }
+    }
+
+    let validate = cli.subcommand_name() == Some("validate");
+    let mut conf = match EnvConf::new(cli) {
         Ok(value) => value,
         Err(err) => {
             error!("Failed to init config -> {}", err);
-            exit(19)
+            exit(SyncError::ConfigError(err).exit_code())
         }
     };
 
-    match run(conf) {
+    configure_ssh(&conf);
+
+    // Held until the process exits, the OS releases it even when killed by a signal.
+    let _lock = match acquire_lock(&conf) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("{:#}", err);
+            exit(SyncError::find(&err).map_or(1, SyncError::exit_code));
+        }
+    };
+
+    let result = if validate {
+        validate_config(&mut conf)
+    } else if conf.get_flag("SERVER_SYNC_LIST_CONTEXTS") {
+        list_contexts(&conf)
+    } else if conf.get_flag("SERVER_SYNC_WATCH") {
+        watch(conf)
+    } else {
+        run(&mut conf)
+    };
+
+    match result {
         Ok(_) => {
             info!("Done!");
             exit(0)
         }
         Err(err) => {
             error!("{}", err);
-            exit(1);
+            exit(SyncError::find(&err).map_or(1, SyncError::exit_code));
         }
     }
 }
 
 fn get_cli() -> ArgMatches {
+    cli().get_matches()
+}
+
+fn cli() -> clap::Command {
     command!()
         .propagate_version(true)
         .args([
-            Arg::new("VERBOSE").short('v').action(ArgAction::Count),
+            Arg::new("VERBOSE")
+                .short('v')
+                .help("Log more, -vv logs everything and -vvvv also logs the variables each file is rendered with.")
+                .action(ArgAction::Count),
+            Arg::new("SERVER_SYNC_QUIET")
+                .short('q')
+                .long("quiet")
+                .env("SERVER_SYNC_QUIET")
+                .help("Only log warnings and errors, besides the summary, and don't print diffs.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("VERBOSE"),
+            Arg::new("SERVER_SYNC_LOG_FORMAT")
+                .long("log-format")
+                .env("SERVER_SYNC_LOG_FORMAT")
+                .help("How logs are written, pretty or json. [default: pretty]"),
+            Arg::new("SERVER_SYNC_NO_COLOR")
+                .long("no-color")
+                .env("SERVER_SYNC_NO_COLOR")
+                .help("Don't colour logs and diffs, this is the default when stdout isn't a terminal or NO_COLOR is set.")
+                .action(ArgAction::SetTrue),
             Arg::new("SERVER_SYNC_ENV")
                 .short('e')
                 .long("env-file")
                 .env("SERVER_SYNC_ENV")
-                .help("The env file to load.")
+                .help("The env files to load, later files override earlier ones.")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .default_value(".server_env"),
             Arg::new("SERVER_SYNC_REPO")
                 .short('r')
@@ -65,6 +224,14 @@ fn get_cli() -> ArgMatches {
                 .long("branch")
                 .env("SERVER_SYNC_BRANCH")
                 .help("The branch to sync from."),
+            Arg::new("SERVER_SYNC_SSH_KEY")
+                .long("ssh-key")
+                .env("SERVER_SYNC_SSH_KEY")
+                .help("The private key to authenticate to the repository over SSH with."),
+            Arg::new("SERVER_SYNC_REF")
+                .long("ref")
+                .env("SERVER_SYNC_REF")
+                .help("The commit, tag or branch to sync instead of the tip of the branch."),
             Arg::new("SERVER_SYNC_DESTINATION")
                 .short('d')
                 .long("dest")
@@ -73,319 +240,2924 @@ fn get_cli() -> ArgMatches {
             Arg::new("SERVER_SYNC_CONTEXTS")
                 .short('c')
                 .long("contexts")
-                .help("The server contexts to use.")
+                .help("The server contexts to use, glob patterns like mc-survival-* match every context directory.")
                 .action(ArgAction::Append),
+            Arg::new("SERVER_SYNC_INCLUDE")
+                .long("include")
+                .env("SERVER_SYNC_INCLUDE")
+                .value_name("GLOB")
+                .help("Only sync files matching the glob, relative to their context. Can be repeated.")
+                .action(ArgAction::Append)
+                .value_delimiter(';'),
+            Arg::new("SERVER_SYNC_EXCLUDE")
+                .long("exclude")
+                .env("SERVER_SYNC_EXCLUDE")
+                .value_name("GLOB")
+                .help("Don't sync files matching the glob, relative to their context. Can be repeated.")
+                .action(ArgAction::Append)
+                .value_delimiter(';'),
+            Arg::new("SERVER_SYNC_STAGING_ROOT")
+                .long("staging-root")
+                .env("SERVER_SYNC_STAGING_ROOT")
+                .value_name("DIR")
+                .help("Write every destination under this directory instead, merging with the live files."),
+            Arg::new("SERVER_SYNC_CONTEXTS_DIR")
+                .long("contexts-dir")
+                .env("SERVER_SYNC_CONTEXTS_DIR")
+                .help("The directory in the repository with a directory for each context. [default: contexts]"),
             Arg::new("SERVER_SYNC_REPO_STORAGE")
                 .long("repo-storage")
                 .env("SERVER_SYNC_REPO_STORAGE")
-                .help("The storage path for the repository.")
+                .help("The storage path for repositories, each is cloned into its own directory under it.")
                 .default_value("/tmp/server-sync/"),
+            Arg::new("SERVER_SYNC_SHALLOW")
+                .long("shallow")
+                .env("SERVER_SYNC_SHALLOW")
+                .help("Only clone and pull the latest commit of the branch.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_SUBMODULES")
+                .long("submodules")
+                .env("SERVER_SYNC_SUBMODULES")
+                .help("Initialize and update the repository's submodules after every pull.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_DIFF_ONLY")
+                .long("diff-only")
+                .env("SERVER_SYNC_DIFF_ONLY")
+                .help("Print the diff of every changed file without writing anything, creating directories or fixing permissions.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_DIFF_FORMAT")
+                .long("diff-format")
+                .env("SERVER_SYNC_DIFF_FORMAT")
+                .help("How diffs are printed, pretty or unified. [default: pretty]"),
+            Arg::new("SERVER_SYNC_VALIDATE")
+                .long("validate")
+                .env("SERVER_SYNC_VALIDATE")
+                .help("Check that rendered JSON, YAML and TOML files parse before writing them, true or false. [default: true]"),
+            Arg::new("SERVER_SYNC_MERGE_EXTENSIONS")
+                .long("merge-extensions")
+                .env("SERVER_SYNC_MERGE_EXTENSIONS")
+                .help("The extensions merged into existing files by default, e.g. yml,yaml,toml. Other files are replaced."),
+            Arg::new("SERVER_SYNC_NORMALIZE_EOL")
+                .long("normalize-eol")
+                .env("SERVER_SYNC_NORMALIZE_EOL")
+                .help("The line endings of rendered files, lf, crlf or preserve. [default: preserve]"),
+            Arg::new("SERVER_SYNC_ENSURE_TRAILING_NEWLINE")
+                .long("ensure-trailing-newline")
+                .env("SERVER_SYNC_ENSURE_TRAILING_NEWLINE")
+                .help("End every rendered file with a line ending.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_IGNORE_WHITESPACE")
+                .long("ignore-whitespace")
+                .env("SERVER_SYNC_IGNORE_WHITESPACE")
+                .help("Leave files that only differ in trailing whitespace and blank lines untouched.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_REQUIRE_OWNERSHIP")
+                .long("require-ownership")
+                .env("SERVER_SYNC_REQUIRE_OWNERSHIP")
+                .help("Fail instead of skipping ownership changes when the owner can't be resolved.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_FILE_MODE")
+                .long("file-mode")
+                .env("SERVER_SYNC_FILE_MODE")
+                .help("The octal mode for synced files. [default: 644]"),
+            Arg::new("SERVER_SYNC_DIR_MODE")
+                .long("dir-mode")
+                .env("SERVER_SYNC_DIR_MODE")
+                .help("The octal mode for directories at the destination. [default: 755]"),
+            Arg::new("SERVER_SYNC_PRESERVE_MODE")
+                .long("preserve-mode")
+                .env("SERVER_SYNC_PRESERVE_MODE")
+                .help("Copy the mode of the source file instead of using the file mode.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_FORCE")
+                .long("force")
+                .env("SERVER_SYNC_FORCE")
+                .help("Overwrite and prune files without backing them up.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_PRUNE")
+                .long("prune")
+                .env("SERVER_SYNC_PRUNE")
+                .help("Back up and remove files synced by a previous run whose source no longer exists.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_REMOVE_SKIPPED")
+                .long("remove-skipped")
+                .env("SERVER_SYNC_REMOVE_SKIPPED")
+                .help("Back up and remove the existing copy of a file skipped by its server-sync-if condition.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_FOLLOW_SYMLINKS")
+                .long("follow-symlinks")
+                .env("SERVER_SYNC_FOLLOW_SYMLINKS")
+                .help("Copy the contents of symlinks in the repository instead of recreating them.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_STRICT_UTF8")
+                .long("strict-utf8")
+                .env("SERVER_SYNC_STRICT_UTF8")
+                .help("Fail when a file with a template extension isn't utf8 instead of copying it as is.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_VERIFY_WRITES")
+                .long("verify-writes")
+                .env("SERVER_SYNC_VERIFY_WRITES")
+                .help("Read every written file back and fail if it doesn't match what was written.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_FOLLOW_DEST_SYMLINKS")
+                .long("follow-dest-symlinks")
+                .env("SERVER_SYNC_FOLLOW_DEST_SYMLINKS")
+                .help("Write to the files symlinks at the destination point at instead of refusing to sync them.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_MODES")
+                .long("modes")
+                .env("SERVER_SYNC_MODES")
+                .help("Octal modes for files matching a glob, e.g. *.sh=755;bin/*=750."),
+            Arg::new("SERVER_SYNC_DELIMITERS")
+                .long("delimiters")
+                .env("SERVER_SYNC_DELIMITERS")
+                .help("Delimiters templates use instead of {{ }}, for every file or those matching a glob, e.g. *.j2=<% %>."),
+            Arg::new("SERVER_SYNC_VARS_FILE")
+                .long("vars-file")
+                .env("SERVER_SYNC_VARS_FILE")
+                .help("Comma separated YAML, TOML or JSON files or globs of structured variables for templates, relative to the repository."),
+            Arg::new("SERVER_SYNC_NO_SERVER_NAME")
+                .long("no-server-name")
+                .env("SERVER_SYNC_NO_SERVER_NAME")
+                .help("Don't inject the context name as the server_name variable.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_FAIL_FAST")
+                .long("fail-fast")
+                .env("SERVER_SYNC_FAIL_FAST")
+                .help("Abort the sync on the first error, including templates that fail to render. (default for other errors)")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("SERVER_SYNC_KEEP_GOING"),
+            Arg::new("SERVER_SYNC_KEEP_GOING")
+                .long("keep-going")
+                .env("SERVER_SYNC_KEEP_GOING")
+                .help("Continue past errors and report all of them once the sync has finished.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_RESTORECON")
+                .long("restorecon")
+                .env("SERVER_SYNC_RESTORECON")
+                .help("Restore the SELinux security context of written files with restorecon.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_PRE_HOOK")
+                .long("pre-hook")
+                .env("SERVER_SYNC_PRE_HOOK")
+                .help("A shell command to validate the rendered files before anything is written."),
+            Arg::new("SERVER_SYNC_BACKUP_SUFFIX")
+                .long("backup-suffix")
+                .env("SERVER_SYNC_BACKUP_SUFFIX")
+                .help("The suffix appended to backups of replaced files. [default: bak]"),
+            Arg::new("SERVER_SYNC_BACKUP_MODE")
+                .long("backup-mode")
+                .env("SERVER_SYNC_BACKUP_MODE")
+                .help("How replaced files are backed up, overwrite, timestamped, numbered or btrfs-snapshot. [default: overwrite]"),
+            Arg::new("SERVER_SYNC_BACKEND")
+                .long("backend")
+                .env("SERVER_SYNC_BACKEND")
+                .help("How files are written, builtin or rsync to deliver each rendered context with rsync. [default: builtin]"),
+            Arg::new("SERVER_SYNC_MAX_BACKUPS")
+                .long("max-backups")
+                .env("SERVER_SYNC_MAX_BACKUPS")
+                .value_name("COUNT")
+                .help("The most timestamped or numbered backups to keep of each file, older ones are removed."),
+            Arg::new("SERVER_SYNC_STATE_FILE")
+                .long("state-file")
+                .env("SERVER_SYNC_STATE_FILE")
+                .help("Where to record the state of the last successful sync, defaults to the repository storage."),
+            Arg::new("SERVER_SYNC_THROTTLE")
+                .long("throttle")
+                .env("SERVER_SYNC_THROTTLE")
+                .value_name("FILES_PER_SEC")
+                .help("Limit how many files are written per second to smooth out disk load."),
+            Arg::new("SERVER_SYNC_WEBHOOK_URL")
+                .long("webhook-url")
+                .env("SERVER_SYNC_WEBHOOK_URL")
+                .help("A URL to POST a JSON summary of every sync to."),
+            Arg::new("SERVER_SYNC_LIST_CONTEXTS")
+                .long("list-contexts")
+                .env("SERVER_SYNC_LIST_CONTEXTS")
+                .help("Update the repository and print the contexts in it without syncing.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_PRINT_CONFIG")
+                .long("print-config")
+                .env("SERVER_SYNC_PRINT_CONFIG")
+                .help("Print every setting, its value and where it came from, then exit.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_OUTPUT")
+                .long("output")
+                .env("SERVER_SYNC_OUTPUT")
+                .help("How listings are printed, text or json. [default: text]"),
+            Arg::new("SERVER_SYNC_INCREMENTAL")
+                .long("incremental")
+                .env("SERVER_SYNC_INCREMENTAL")
+                .help("Only sync files changed since the last synced commit.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_SINCE")
+                .long("since")
+                .env("SERVER_SYNC_SINCE")
+                .value_name("COMMIT")
+                .help("Only sync files changed since this commit instead of the last synced commit."),
+            Arg::new("SERVER_SYNC_ONLY_CHANGED_CONTEXTS")
+                .long("only-changed-contexts")
+                .env("SERVER_SYNC_ONLY_CHANGED_CONTEXTS")
+                .help("Skip contexts with nothing changed since they were last synced.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_WAIT")
+                .long("wait")
+                .env("SERVER_SYNC_WAIT")
+                .help("Wait for another running sync to finish instead of exiting.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_WATCH")
+                .long("watch")
+                .env("SERVER_SYNC_WATCH")
+                .help("Keep running and sync again whenever new commits are pulled.")
+                .action(ArgAction::SetTrue),
+            Arg::new("SERVER_SYNC_WATCH_INTERVAL")
+                .long("watch-interval")
+                .env("SERVER_SYNC_WATCH_INTERVAL")
+                .value_name("SECONDS")
+                .help("How often the repository is pulled in watch mode. [default: 60]"),
+            Arg::new("SERVER_SYNC_JOBS")
+                .short('j')
+                .long("jobs")
+                .env("SERVER_SYNC_JOBS")
+                .help("The number of files to process in parallel. [default: one per CPU]"),
         ])
-        .get_matches()
+        .subcommand(
+            clap::Command::new("validate")
+                .about("Checks the configuration and templates without syncing anything"),
+        )
 }
 
 fn start_logger(matches: &ArgMatches) -> anyhow::Result<()> {
     let level = matches.get_count("VERBOSE");
     let level = match level {
+        _ if matches.get_flag("SERVER_SYNC_QUIET") => LevelFilter::Warn,
         2 => LevelFilter::Trace,
         1 => LevelFilter::Debug,
         0 => LevelFilter::Info,
         _ => LevelFilter::Trace,
     };
 
-    TermLogger::init(
-        level,
-        ConfigBuilder::new()
-            .set_time_level(LevelFilter::Off)
-            .build(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )?;
+    let format = matches
+        .get_one::<String>("SERVER_SYNC_LOG_FORMAT")
+        .map(|format| format.parse::<LogFormat>())
+        .transpose()?
+        .unwrap_or(LogFormat::Pretty);
+
+    // The log macros colour messages themselves, so they're stripped when the output isn't a terminal.
+    let colour = !matches.get_flag("SERVER_SYNC_NO_COLOR")
+        && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && std::io::stdout().is_terminal();
+
+    let config = ConfigBuilder::new()
+        .set_time_level(LevelFilter::Off)
+        .build();
+    match (format, colour) {
+        (LogFormat::Pretty, true) => {
+            TermLogger::init(level, config, TerminalMode::Mixed, ColorChoice::Auto)?
+        }
+        (LogFormat::Pretty, false) => PlainLogger::init(
+            level,
+            TermLogger::new(level, config, TerminalMode::Mixed, ColorChoice::Never),
+        )?,
+        (LogFormat::Json, _) => JsonLogger::init(level)?,
+    }
 
     info!("Logger started at level {}", level);
 
     Ok(())
 }
 
-fn run(conf: EnvConf) -> anyhow::Result<()> {
-    let repo_str = conf
-        .get_env("SERVER_SYNC_REPO_STORAGE")
-        .context("Get repo storage location")?;
-    let repo_dir = Path::new(&repo_str);
-    sync_repository(&conf, &repo_dir).context("Sync repo")?;
+fn run(conf: &mut EnvConf) -> anyhow::Result<()> {
+    let repo_dir = conf.repo_dir.clone();
+    let started = Instant::now();
+    let mut report = SyncReport::default();
+    let result = sync_repository(conf, &repo_dir)
+        .context("Sync repo")
+        .and_then(|_| sync_contexts(conf, &repo_dir, &mut report));
 
-    let mut handlebars = new_handlerbars().context("Initialize handlebars")?;
+    if let Some(url) = conf.get_env("SERVER_SYNC_WEBHOOK_URL") {
+        webhook::post_summary(&url, &report, started.elapsed(), result.as_ref().err());
+    }
 
-    debug!("Variables: {:?}", &conf.get_variables());
+    result
+}
 
-    for context in conf.get_contexts() {
-        if !context.source_root.exists() || !context.source_root.is_dir() {
-            return return Err(format_err!(
-                "Server source root doesn't exist or is not a directory: {}",
-                context.source_root.display()
-            ));
+/// Syncs once and then keeps pulling the repository, syncing again whenever there are new commits.
+/// A local source has no commits to compare so it's synced again every interval.
+///
+/// A failed sync or pull is logged and retried on the next interval, interrupting waits for
+/// the current sync to finish before stopping.
+fn watch(mut conf: EnvConf) -> anyhow::Result<()> {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let handler_stopped = stopped.clone();
+    ctrlc::set_handler(move || handler_stopped.store(true, Ordering::SeqCst))
+        .context("Set interrupt handler")?;
+
+    let repo_dir = conf.repo_dir.clone();
+
+    if let Err(err) = run(&mut conf) {
+        error!("{:#}", err);
+    }
+    let mut synced = source_revision(&conf).ok().flatten();
+
+    info!(
+        "Watching for new commits every {}s",
+        conf.watch_interval.as_secs()
+    );
+    loop {
+        let deadline = Instant::now() + conf.watch_interval;
+        while Instant::now() < deadline && !stopped.load(Ordering::SeqCst) {
+            sleep(WATCH_POLL);
         }
 
-        info!("Processing context {}", context.name);
-        debug!("Source root: {}", context.source_root.display());
+        if stopped.load(Ordering::SeqCst) {
+            info!("Stopping watch");
+            return Ok(());
+        }
+
+        let head = sync_repository(&conf, &repo_dir)
+            .context("Sync repo")
+            .and_then(|_| source_revision(&conf));
+        match head {
+            Ok(Some(head)) if synced.as_ref() == Some(&head) => {
+                debug!("No new commits");
+            }
+            Ok(head) => {
+                match &head {
+                    Some(head) => info!("Syncing commit {}", head),
+                    None => info!("Syncing local source"),
+                }
+                let started = Instant::now();
+                let mut report = SyncReport::default();
+                let result = sync_contexts(&mut conf, &repo_dir, &mut report);
+
+                if let Some(url) = conf.get_env("SERVER_SYNC_WEBHOOK_URL") {
+                    webhook::post_summary(&url, &report, started.elapsed(), result.as_ref().err());
+                }
+
+                match result {
+                    Ok(_) => info!("Done!"),
+                    Err(err) => error!("{:#}", err),
+                }
+                synced = head;
+            }
+            Err(err) => error!("{:#}", err),
+        }
+    }
+}
 
-        walk_directory(&mut handlebars, &context, &conf)?;
+/// Updates the repository and prints the name of every context directory in it.
+fn list_contexts(conf: &EnvConf) -> anyhow::Result<()> {
+    sync_repository(conf, &conf.repo_dir).context("Sync repo")?;
+
+    conf.check_contexts_dir()?;
+    let mut names = read_dir(&conf.contexts_dir)
+        .with_context(|| format!("Read {}", conf.contexts_dir.display()))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let format = conf
+        .get_env("SERVER_SYNC_OUTPUT")
+        .map(|format| format.parse::<OutputFormat>())
+        .transpose()?
+        .unwrap_or(OutputFormat::Text);
+
+    // Written straight to stdout so scripts don't have to strip the log prefix.
+    match format {
+        OutputFormat::Text => names.iter().for_each(|name| println!("{}", name)),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&names)?),
     }
 
     Ok(())
 }
 
-fn git_output(cmd: &mut Command, context: String) -> anyhow::Result<()> {
-    let output = cmd.output().context(context)?;
-    trace!(
-        "Git output -> <blue>{}",
-        String::from_utf8_lossy(&output.stdout).trim()
+/// Locks a file next to the repository storage, so overlapping runs don't race on the
+/// repository and destinations. Without `--wait` another run holding it is an error.
+fn acquire_lock(conf: &EnvConf) -> anyhow::Result<File> {
+    let storage = PathBuf::from(
+        conf.get_env("SERVER_SYNC_REPO_STORAGE")
+            .context("Get repository path")?,
     );
+    let name = storage
+        .file_name()
+        .context("Get repository storage name")?
+        .to_string_lossy();
+    let path = storage.with_file_name(format!("{}.lock", name));
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).context("Create lock directory")?;
+    }
+
+    let file = File::create(&path).with_context(|| format!("Create lock {}", path.display()))?;
+    match file.try_lock() {
+        Ok(_) => {}
+        Err(TryLockError::WouldBlock) if conf.get_flag("SERVER_SYNC_WAIT") => {
+            info!("Waiting for the sync holding {} to finish", path.display());
+            file.lock()
+                .with_context(|| format!("Lock {}", path.display()))?;
+        }
+        Err(TryLockError::WouldBlock) => return Err(SyncError::Locked(path).into()),
+        Err(TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("Lock {}", path.display()))
+        }
+    }
+
+    debug!("Locked {}", path.display());
+    Ok(file)
+}
+
+/// Prints every setting with the value a sync would use and where it came from,
+/// this doesn't need a valid configuration so it can show what's wrong with one.
+fn print_config(matches: &ArgMatches) -> anyhow::Result<()> {
+    let cli = cli();
+    let names = cli
+        .get_arguments()
+        .filter(|arg| arg.get_env().is_some() && arg.get_id() != "SERVER_SYNC_PRINT_CONFIG")
+        .map(|arg| arg.get_id().as_str())
+        .collect::<Vec<_>>();
+    let settings = config::resolve_settings(matches, &names);
+
+    let format = settings
+        .iter()
+        .find(|setting| setting.name == "SERVER_SYNC_OUTPUT")
+        .and_then(|setting| setting.value.as_ref())
+        .map(|(format, _)| format.parse::<OutputFormat>())
+        .transpose()?
+        .unwrap_or(OutputFormat::Text);
+
+    let value = |setting: &Setting| {
+        setting
+            .value
+            .as_ref()
+            .map(|(value, origin)| (redact(&setting.name, value, *origin), origin.to_string()))
+    };
+    match format {
+        OutputFormat::Text => {
+            for setting in &settings {
+                match value(setting) {
+                    Some((value, origin)) => println!("{}={} ({})", setting.name, value, origin),
+                    None => println!("{} is unset", setting.name),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let settings = settings
+                .iter()
+                .map(|setting| {
+                    let value = match value(setting) {
+                        Some((value, origin)) => json!({ "value": value, "source": origin }),
+                        None => Value::Null,
+                    };
+                    (setting.name.to_owned(), value)
+                })
+                .collect::<Map<String, Value>>();
+            println!("{}", serde_json::to_string(&settings)?);
+        }
+    }
 
     Ok(())
 }
 
-fn sync_repository(conf: &EnvConf, repo_dir: &Path) -> anyhow::Result<()> {
-    let repo_url = conf.get_env("SERVER_SYNC_REPO").unwrap();
-    let repo_branch = conf
-        .get_env("SERVER_SYNC_BRANCH")
-        .unwrap_or("master".to_string());
+/// Hides values that are likely to be secrets, like values read from secret files,
+/// webhook URLs that carry their token and a repository URL with a password.
+fn redact(name: &str, value: &str, origin: Origin) -> String {
+    if origin == Origin::SecretFile || is_secret_name(name) {
+        return String::from("<redacted>");
+    }
 
-    if !repo_dir.exists() {
-        info!("Cloning repository {}", &repo_url);
+    if let Some((scheme, rest)) = value.split_once("://") {
+        if let Some((userinfo, host)) = rest.split_once('@') {
+            if let Some((user, _)) = userinfo.split_once(':').filter(|_| !userinfo.contains('/')) {
+                return format!("{}://{}:<redacted>@{}", scheme, user, host);
+            }
+        }
+    }
 
-        let mut cmd = Command::new("git");
-        cmd.arg("clone").arg(&repo_url).arg(&repo_dir);
-        git_output(&mut cmd, "Clone repository".to_string())?;
-    } else {
-        info!("Updating repository {}", &repo_url);
+    value.to_string()
+}
 
-        let mut cmd = Command::new("git");
-        cmd.arg("-C").arg(&repo_dir).arg("pull");
-        git_output(&mut cmd, "Update repository".to_string())?;
+/// Checks if a setting or variable name looks like it holds a secret.
+fn is_secret_name(name: &str) -> bool {
+    const SECRETS: [&str; 5] = ["PASSPHRASE", "PASSWORD", "TOKEN", "SECRET", "WEBHOOK"];
+    SECRETS
+        .iter()
+        .any(|secret| name.to_uppercase().contains(secret))
+}
+
+/// Checks the configuration is coherent without writing to the destination,
+/// reporting every problem found instead of stopping at the first.
+///
+/// The repository storage is still updated so the contexts and templates can be checked.
+fn validate_config(conf: &mut EnvConf) -> anyhow::Result<()> {
+    let repo_dir = conf.repo_dir.clone();
+    let mut problems = vec![];
+
+    info!("Checking repository");
+    match check_remote(conf).and_then(|_| sync_repository(conf, &repo_dir).context("Sync repo")) {
+        Ok(_) => check_contexts(conf, &repo_dir, &mut problems),
+        Err(err) => problems.push(err),
     }
 
-    info!("Checking out branch {}", &repo_branch);
+    info!("Checking destinations");
+    let mut destinations = conf
+        .get_contexts()
+        .iter()
+        .map(|context| context.get_destination(conf).to_path_buf())
+        .collect::<Vec<_>>();
+    destinations.sort();
+    destinations.dedup();
+    for destination in destinations {
+        if let Err(err) = check_writable(&destination) {
+            problems.push(err.context(format!(
+                "Destination {} isn't writable",
+                destination.display()
+            )));
+        }
+    }
+
+    if let Err(err) = conf.check_ownership() {
+        problems.push(err.context("Resolve the owner for synced files"));
+    }
 
-    let mut cmd = Command::new("git");
-    cmd.arg("-C")
-        .arg(&repo_dir)
-        .arg("checkout")
-        .arg(&repo_branch);
+    if !problems.is_empty() {
+        error!("Found {} problems:", problems.len());
+        for problem in &problems {
+            error!("  {:#}", problem);
+        }
 
-    git_output(&mut cmd, "Checkout branch".to_string())?;
+        return Err(format_err!("Configuration has {} problems", problems.len()));
+    }
 
+    info!("Configuration is valid");
     Ok(())
 }
 
-fn walk_directory(
-    handlebars: &mut Handlebars,
-    context: &ServerContext,
-    conf: &EnvConf,
-) -> anyhow::Result<()> {
-    let walker = WalkDir::new(&context.source_root)
-        .same_file_system(true)
-        .into_iter()
-        .filter(|e| e.is_ok())
-        .filter(|e| e.as_ref().unwrap().file_type().is_file())
-        .map(|e| e.unwrap());
+/// Checks the repository can be reached and has the branch without cloning it.
+fn check_remote(conf: &EnvConf) -> anyhow::Result<()> {
+    if conf.local_source {
+        return Ok(());
+    }
+
+    let repo_url = conf.get_env("SERVER_SYNC_REPO").context("Get repository")?;
+    let repo_branch = conf
+        .get_env("SERVER_SYNC_BRANCH")
+        .unwrap_or("master".to_string());
 
-    let mut non_utf8 = vec![];
+    let output = Command::new("git")
+        .args(["ls-remote", "--exit-code", "--heads"])
+        .arg(&repo_url)
+        .arg(&repo_branch)
+        .output()
+        .context("Run git ls-remote")?;
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        // ls-remote exits with 2 when the repository was reached but no ref matched.
+        Some(2) => Err(format_err!(
+            "Branch {} doesn't exist in {}",
+            repo_branch,
+            repo_url
+        )),
+        _ => Err(format_err!(
+            "Repository {} isn't reachable -> {}",
+            repo_url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
 
-    for entry in walker {
-        let relative_path = entry
-            .path()
-            .strip_prefix(&context.source_root)
-            .context("Get relative path")?;
-        let destination_path = conf.destination_root.join(relative_path);
+/// Checks every context exists and every template, partial and manifest in them parses.
+fn check_contexts(conf: &mut EnvConf, repo_dir: &Path, problems: &mut Vec<anyhow::Error>) {
+    if let Err(err) = conf.check_contexts_dir() {
+        problems.push(err);
+        return;
+    }
+    if let Err(err) = conf.expand_contexts().context("Expand contexts") {
+        problems.push(err);
+    }
+    if let Err(err) = conf.load_vars_file().context("Load vars file") {
+        problems.push(err);
+    }
+    if let Err(err) = new_handlerbars(repo_dir).context("Initialize handlebars") {
+        problems.push(err);
+    }
 
-        trace!("Processing file {}", relative_path.display());
+    for context in conf.contexts.iter_mut() {
+        if !context.source_root.is_dir() {
+            problems.push(format_err!(
+                "Context {} doesn't exist at {}",
+                context.name,
+                context.source_root.display()
+            ));
+            continue;
+        }
 
-        let contents = match get_contents(entry.path()) {
-            None => {
-                non_utf8.push((entry.path().to_owned(), destination_path));
-                continue;
-            }
-            Some(value) => value,
-        };
+        if let Err(err) = context.load_manifests(&conf.repo_dir) {
+            problems.push(err.context(format!("Load manifests for context {}", context.name)));
+        }
+    }
 
-        let rendered = render_entry(handlebars, &context, &conf, &contents, &entry)
-            .context("Render source")?;
-        let parent = destination_path.parent().expect("File was at / level???");
+    for context in conf.get_contexts() {
+        if !context.source_root.is_dir() {
+            continue;
+        }
 
-        trace!(
-            "Templating {} to {}",
-            &entry.path().display(),
-            &destination_path.display()
-        );
+        info!("Checking templates in context {}", context.name);
+        for source in source_files(context, conf) {
+            let relative_path = context.relative_path(&source).unwrap();
+            let raw_path = relative_path.to_string_lossy();
+            if raw_path.contains("{{") {
+                if let Err(err) = handlebars::Template::compile(&raw_path) {
+                    problems.push(
+                        anyhow::Error::new(err)
+                            .context(format!("Parse path template {}", source.display())),
+                    );
+                }
+            }
 
-        ensure_ancestors(parent, &conf)?;
+            if source.is_symlink() && !conf.get_flag("SERVER_SYNC_FOLLOW_SYMLINKS") {
+                continue;
+            }
 
-        if check_existing(&destination_path, &rendered)? {
-            debug!("File {} is up to date", destination_path.display());
-        } else {
-            backup_and_write(&destination_path, rendered.as_bytes())?;
+            let contents = match read_template(context, conf, &source, relative_path) {
+                Ok(Some(contents)) => contents,
+                Ok(None) => continue,
+                Err(err) => {
+                    problems.push(err);
+                    continue;
+                }
+            };
+
+            let contents = match prepare_template(context, conf, relative_path, &contents) {
+                Ok((_, _, contents)) => contents,
+                Err(err) => {
+                    problems.push(err.context(format!("Parse template {}", source.display())));
+                    continue;
+                }
+            };
+            if let Err(err) = handlebars::Template::compile(&contents) {
+                problems.push(
+                    anyhow::Error::new(err).context(format!("Parse template {}", source.display())),
+                );
+            }
         }
+    }
+}
 
-        fix_permissions(&destination_path, &conf)?;
+/// Checks a file can be created in the directory, or the closest ancestor that exists
+/// as the missing directories would be created there.
+fn check_writable(directory: &Path) -> anyhow::Result<()> {
+    let existing = directory
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .context("No ancestor exists")?;
+    if !existing.is_dir() {
+        return Err(format_err!("{} isn't a directory", existing.display()));
     }
 
-    // TODO -> This is a bit of a hack, but it works for now.
-    for (source, dest) in non_utf8 {
-        trace!("Processing file {}", source.display());
+    let probe = existing.join(format!(".server-sync-validate-{}", std::process::id()));
+    File::create(&probe).with_context(|| format!("Create a file in {}", existing.display()))?;
+    remove_file(&probe).with_context(|| format!("Remove {}", probe.display()))?;
 
-        ensure_ancestors(
-            &dest.parent().context("Get destination parent folder.")?,
-            &conf,
-        )?;
+    Ok(())
+}
 
-        let buf = read(source).context("Read source file")?;
-        if read(&dest)
-            .context("Read existing file")
-            .map(|e| e == buf)
-            .unwrap_or(false)
-        {
-            debug!("File {} is up to date", dest.display());
-        } else {
-            backup_and_write(&dest, &buf)?;
+/// Syncs every context from the repository as it's currently checked out.
+fn sync_contexts(
+    conf: &mut EnvConf,
+    repo_dir: &Path,
+    report: &mut SyncReport,
+) -> anyhow::Result<()> {
+    conf.check_contexts_dir()?;
+    conf.expand_contexts().context("Expand contexts")?;
+    conf.load_vars_file().context("Load vars file")?;
+    if !conf.local_source {
+        conf.git_vars = git_metadata(repo_dir).context("Read commit metadata")?;
+    }
+
+    for context in conf.contexts.iter_mut() {
+        context
+            .load_manifests(&conf.repo_dir)
+            .with_context(|| format!("Load manifests for context {}", context.name))?;
+    }
+
+    // A bad destination would otherwise only fail deep in the walk, after directories were made.
+    if !conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+        for context in conf.get_contexts() {
+            let destination = context.get_destination(conf);
+            if conf.staging_root.is_some() {
+                create_dir_all(destination).with_context(|| {
+                    format!("Create staged destination {}", destination.display())
+                })?;
+            }
+            check_writable(destination)
+                .with_context(|| format!("Destination {} isn't writable", destination.display()))?;
         }
+    }
 
-        fix_permissions(&dest, &conf)?;
+    let handlebars = new_handlerbars(repo_dir).context("Initialize handlebars")?;
+    let use_rsync = conf.backend == rsync::Backend::Rsync
+        && !conf.get_flag("SERVER_SYNC_DIFF_ONLY")
+        && rsync::usable(conf);
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(conf.jobs)
+        .build()
+        .context("Create thread pool")?;
+
+    if let Some(command) = conf.get_env("SERVER_SYNC_PRE_HOOK") {
+        let staging = env::temp_dir().join(format!("server-sync-staging-{}", std::process::id()));
+        let result = stage_contexts(&handlebars, conf, &staging)
+            .context("Stage rendered files")
+            .and_then(|_| hooks::run_pre_sync(&command, &staging));
+        let _ = remove_dir_all(&staging);
+
+        result.context("Run pre sync hook")?;
     }
 
-    Ok(())
-}
+    debug!("Variables: {:?}", &conf.get_variables());
 
-fn backup_and_write(destination: &Path, contents: &[u8]) -> anyhow::Result<()> {
-    if destination.exists() {
-        trace!("Backing up {}", destination.display());
-        let backup_path = Path::new(&destination).with_extension("bak");
-        rename(&destination, &backup_path).context("Rename old file")?;
+    if conf.get_flag("SERVER_SYNC_FORCE") {
+        warn!("Backups are disabled for this run, replaced files will be overwritten");
+    } else if conf.backup_mode == BackupMode::BtrfsSnapshot
+        && !conf.get_flag("SERVER_SYNC_DIFF_ONLY")
+    {
+        let destinations = conf
+            .get_contexts()
+            .iter()
+            .map(|context| context.get_destination(conf))
+            .collect::<BTreeSet<_>>();
+        for destination in destinations {
+            let snapshot = backup::snapshot_destination(destination, &conf.backup_suffix)
+                .with_context(|| format!("Snapshot destination {}", destination.display()))?;
+            info!(
+                "Snapshotted {} to {}",
+                destination.display(),
+                snapshot.display()
+            );
+        }
+    }
+
+    if !conf.get_flag("SERVER_SYNC_NO_SERVER_NAME") {
+        let global = conf.get_template_data().contains_key("server_name");
+        for context in conf.get_contexts() {
+            if global || context.vars.contains_key("server_name") {
+                warn!("Variable server_name is set for context {} but will be overwritten by the context name, use --no-server-name to keep it", context.name);
+            }
+        }
     }
 
-    trace!("Writing {}", destination.display());
-    let mut file = File::create(&destination).context("Create file at destination")?;
-    file.write_all(contents).context("Write out all bytes")?;
+    // Kept in the repository storage even for a local source, which may be managed by another tool.
+    let state_path = conf
+        .get_env("SERVER_SYNC_STATE_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| conf.storage_dir.join(STATE_FILE));
+    let cache = FileCache::load(&state_path);
+    let mut managed = state::read_managed(&state_path);
+    let mut synced_contexts = state::read_contexts(&state_path);
+    let head = source_revision(conf).context("Get synced commit")?;
 
-    Ok(())
-}
+    let since = conf.get_env("SERVER_SYNC_SINCE");
+    let incremental = since.is_some() || conf.get_flag("SERVER_SYNC_INCREMENTAL");
+    let only_changed = conf.get_flag("SERVER_SYNC_ONLY_CHANGED_CONTEXTS");
+    // Contexts last synced at the same commit share the diff against it.
+    let mut diffs = BTreeMap::new();
 
-fn ensure_ancestors(parent: &Path, conf: &EnvConf) -> anyhow::Result<()> {
-    let ancestors_dirs = parent.ancestors().collect::<Vec<&Path>>();
+    let mut errors = vec![];
 
-    for ancestor in ancestors_dirs.iter().rev() {
-        if !ancestor.starts_with(&conf.destination_root) {
+    for context in conf.get_contexts() {
+        if !context.source_root.exists() || !context.source_root.is_dir() {
+            let err = format_err!(
+                "Server source root doesn't exist or is not a directory: {}",
+                context.source_root.display()
+            );
+            collect_error(conf, &mut errors, Err(err))?;
             continue;
         }
 
-        if !ancestor.exists() {
-            create_dir(ancestor).context("Create ancestor directory")?;
+        let base = since.clone().or_else(|| {
+            synced_contexts
+                .get(&context.name)
+                .and_then(|state| state.commit.clone())
+        });
+        let changes = match (incremental || only_changed, base) {
+            (false, _) => None,
+            (true, None) => {
+                info!(
+                    "No synced commit is recorded for context {}, syncing every file",
+                    context.name
+                );
+                None
+            }
+            (true, Some(base)) => diffs
+                .entry(base.clone())
+                .or_insert_with(|| changed_since(conf, repo_dir, &base))
+                .clone(),
+        };
+        let changes = context_changes(
+            context,
+            conf,
+            changes.as_ref(),
+            managed.contains_key(&context.name),
+        );
+        let vars = vars_hash(context, conf);
+        let changes = match changes {
+            Some(_)
+                if synced_contexts
+                    .get(&context.name)
+                    .and_then(|state| state.vars)
+                    != Some(vars) =>
+            {
+                info!(
+                    "Variables of context {} changed, syncing every file",
+                    context.name
+                );
+                None
+            }
+            changes => changes,
+        };
+
+        if only_changed && changes.as_ref().is_some_and(BTreeSet::is_empty) {
+            info!("Skipping context {}, nothing in it changed", context.name);
+            for path in managed.get(&context.name).into_iter().flatten() {
+                cache.keep(&context.get_destination(conf).join(path));
+            }
+            if let Some(state) = synced_contexts.get_mut(&context.name) {
+                state.commit = head.clone();
+            }
+            continue;
+        }
+
+        info!("Processing context {}", context.name);
+        debug!("Source root: {}", context.source_root.display());
+        debug!("Destination: {}", context.get_destination(conf).display());
+
+        let errors_before = errors.len();
+        let only = match incremental {
+            true => changes,
+            false => None,
+        };
+        let (synced, changed) = match use_rsync {
+            true => match rsync_context(&handlebars, context, conf, report)
+                .with_context(|| format!("Deliver context {} with rsync", context.name))
+            {
+                Ok(delivered) => delivered,
+                Err(err) => {
+                    // Still managed as before, nothing is known about what was delivered.
+                    collect_error(conf, &mut errors, Err(err))?;
+                    continue;
+                }
+            },
+            false => pool.install(|| {
+                walk_directory(
+                    &handlebars,
+                    context,
+                    conf,
+                    &cache,
+                    report,
+                    &mut errors,
+                    only.as_ref(),
+                )
+            })?,
+        };
+        synced_contexts.insert(
+            context.name.to_owned(),
+            ContextState {
+                files: synced.len(),
+                commit: head.clone(),
+                vars: Some(vars),
+            },
+        );
+
+        let previous = managed.remove(&context.name).unwrap_or_default();
+        let mut current = synced.into_iter().collect::<BTreeSet<_>>();
+        let stale = previous.difference(&current).cloned().collect::<Vec<_>>();
+        if conf.get_flag("SERVER_SYNC_PRUNE") && errors.len() > errors_before {
+            // A file that failed may not have a known destination, so it could look stale.
+            warn!(
+                "Not pruning context {} as some of its files failed to sync",
+                context.name
+            );
+            current.extend(stale);
+        } else if conf.get_flag("SERVER_SYNC_PRUNE") {
+            match prune_context(context, conf, &stale, report)
+                .with_context(|| format!("Prune context {}", context.name))
+            {
+                Ok(kept) => current.extend(kept),
+                Err(err) => {
+                    collect_error(conf, &mut errors, Err(err))?;
+                    current.extend(stale);
+                }
+            }
+        } else {
+            // Still managed until it's pruned, so a later run with --prune can remove it.
+            current.extend(stale);
+        }
+        managed.insert(context.name.to_owned(), current);
+
+        if !changed.is_empty() && !conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+            let result = hooks::run_post_sync(context, conf, &changed)
+                .with_context(|| format!("Run post sync hook for context {}", context.name));
+            collect_error(conf, &mut errors, result)?;
+        }
+    }
+
+    let summary = match conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+        true => format!("Summary of changes that weren't written: {}", report),
+        false => format!("Summary: {}", report),
+    };
+    // Quiet mode doesn't log info, but the summary is what a script running it still wants to see.
+    match conf.get_flag("SERVER_SYNC_QUIET") {
+        true => println!("{}", summary),
+        false => info!("{}", summary),
+    }
+
+    if !errors.is_empty() {
+        error!("Sync finished with {} errors:", errors.len());
+        for err in &errors {
+            error!("  {:#}", err);
         }
 
-        fix_permissions(&ancestor, &conf)?;
+        return Err(format_err!("{} errors occurred during sync", errors.len()));
+    }
+
+    if conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+        return Ok(());
+    }
+
+    let state = SyncState {
+        commit: head,
+        branch: conf
+            .get_env("SERVER_SYNC_BRANCH")
+            .unwrap_or("master".to_string()),
+        contexts: synced_contexts,
+        files: cache.into_files(),
+        managed,
+    };
+
+    debug!("Writing sync state to {}", state_path.display());
+    if let Some(parent) = state_path.parent() {
+        create_dir_all(parent).context("Create sync state directory")?;
     }
+    state.write(&state_path).context("Write sync state")?;
 
     Ok(())
 }
 
-fn get_contents<P: AsRef<Path>>(path: P) -> Option<String> {
-    let mut source = vec![];
-    File::open(path).unwrap().read_to_end(&mut source).unwrap();
-    return match simdutf8::basic::from_utf8(&source) {
-        Ok(contents) => Some(contents.to_string()),
-        Err(_) => None,
+/// Makes git authenticate with the configured SSH key, without ever prompting for a password.
+///
+/// This has to happen before any threads are started as it sets the environment inherited by git.
+fn configure_ssh(conf: &EnvConf) {
+    let key = match &conf.ssh_key {
+        Some(key) => key,
+        None => return,
     };
-}
 
-fn render_entry(
-    handlebars: &mut Handlebars,
-    context: &ServerContext,
-    conf: &EnvConf,
-    contents: &String,
-    entry: &DirEntry,
-) -> anyhow::Result<String> {
-    let mut variables_cloned = conf.get_variables().clone();
-    variables_cloned.insert(String::from("server_name"), context.name.to_owned());
+    let mut ssh_command = format!(
+        "ssh -i '{}' -o IdentitiesOnly=yes",
+        key.display().to_string().replace('\'', "'\\''")
+    );
 
-    handlebars.register_template_string(&entry.file_name().to_string_lossy(), &contents)?;
+    match conf.get_env("SERVER_SYNC_SSH_KEY_PASSPHRASE") {
+        // ssh only reads the passphrase from its askpass program, which is this binary
+        // answering with the passphrase it's given through the environment.
+        Some(passphrase) => {
+            ssh_command
+                .push_str(" -o PasswordAuthentication=no -o KbdInteractiveAuthentication=no");
+            env::set_var("SERVER_SYNC_SSH_KEY_PASSPHRASE", passphrase);
+            env::set_var(ASKPASS_ENV, "1");
+            env::set_var("SSH_ASKPASS_REQUIRE", "force");
+            if let Ok(exe) = env::current_exe() {
+                env::set_var("SSH_ASKPASS", exe);
+            }
+        }
+        None => ssh_command.push_str(" -o BatchMode=yes"),
+    }
 
-    return handlebars
-        .render(&entry.file_name().to_string_lossy(), &variables_cloned)
-        .ok()
-        .context("Rendering template");
+    debug!("Using SSH key {}", key.display());
+    env::set_var("GIT_SSH_COMMAND", ssh_command);
 }
 
-fn check_existing(destination: &Path, rendered: &String) -> anyhow::Result<bool> {
-    if !destination.exists() {
-        return Ok(false);
+/// Gets the passphrase to answer ssh with when it runs this binary to ask for the key's passphrase.
+fn askpass_passphrase() -> Option<String> {
+    env::var_os(ASKPASS_ENV)?;
+
+    let prompt = env::args().nth(1)?;
+    if env::args().count() != 2 || !prompt.starts_with("Enter passphrase") {
+        return None;
     }
 
-    let existing_contents = match get_contents(&destination) {
-        None => return Ok(false),
-        Some(value) => value,
-    };
+    env::var("SERVER_SYNC_SSH_KEY_PASSPHRASE").ok()
+}
 
-    let diff = TextDiff::from_lines(&existing_contents, &rendered);
-    for change in diff.iter_all_changes() {
-        let sign = match change.tag() {
-            ChangeTag::Delete => "<red>-",
-            ChangeTag::Insert => "<green>+",
-            ChangeTag::Equal => continue,
-        };
+/// Lists the paths in the repository changed since a commit, relative to the repository.
+///
+/// Nothing is returned when everything should be synced, because the source is local
+/// or the commit isn't in the repository.
+fn changed_since(conf: &EnvConf, repo_dir: &Path, since: &str) -> Option<BTreeSet<PathBuf>> {
+    if conf.local_source {
+        warn!("A local source has no commits to compare, syncing every file");
+        return None;
+    }
 
-        info!("{} {}", sign, change.to_string().trim());
+    let exists = Command::new("git")
+        .arg("-C")
+        .arg(repo_dir)
+        .args(["cat-file", "-e"])
+        .arg(format!("{}^{{commit}}", since))
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !exists {
+        info!(
+            "Commit {} isn't in the repository, syncing every file",
+            since
+        );
+        return None;
     }
 
-    if diff.ratio() == 1.0 {
-        return Ok(true);
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_dir)
+        .args(["diff", "--name-only", "--no-renames", "-z"])
+        .arg(since)
+        .arg("HEAD")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let changes = output
+                .stdout
+                .split(|byte| *byte == 0)
+                .filter(|path| !path.is_empty())
+                .map(|path| PathBuf::from(String::from_utf8_lossy(path).to_string()))
+                .collect::<BTreeSet<_>>();
+
+            info!("{} paths changed since {}", changes.len(), since);
+            Some(changes)
+        }
+        Ok(output) => {
+            warn!(
+                "Failed to diff against {}, syncing every file -> {}",
+                since,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            warn!(
+                "Failed to diff against {}, syncing every file -> {}",
+                since, err
+            );
+            None
+        }
+    }
+}
+
+/// Narrows the repository changes to the files of a context, relative to its source root.
+///
+/// The whole context is synced when anything outside of the contexts directory changed, like partials or
+/// the vars file, when one of its manifests or exports changed, or when it wasn't synced before.
+fn context_changes(
+    context: &ServerContext,
+    conf: &EnvConf,
+    changes: Option<&BTreeSet<PathBuf>>,
+    synced_before: bool,
+) -> Option<BTreeSet<PathBuf>> {
+    let changes = changes?;
+    let contexts_dir = conf.contexts_dir.strip_prefix(&conf.repo_dir).ok()?;
+    if !synced_before || changes.iter().any(|path| !path.starts_with(contexts_dir)) {
+        return None;
     }
 
-    return Ok(false);
+    // Changes to an included context are changes to the files layered from it.
+    let prefixes = context
+        .layers()
+        .map(|root| root.strip_prefix(&conf.repo_dir).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let changes = changes
+        .iter()
+        .filter_map(|path| {
+            prefixes
+                .iter()
+                .find_map(|prefix| path.strip_prefix(prefix).ok())
+        })
+        .map(Path::to_path_buf)
+        .collect::<BTreeSet<_>>();
+
+    // A changed directory is a submodule that moved, git doesn't list the files that changed in it.
+    // A changed .gitattributes can mark or unmark files that didn't change themselves.
+    let reconfigured = changes.iter().any(|path| {
+        context.is_manifest(&context.source_root.join(path))
+            || context.exports.iter().any(|(_, export)| export == path)
+            || context.source_path(path).is_dir()
+            || path.file_name() == Some(GIT_ATTRIBUTES_FILE.as_ref())
+    });
+    match reconfigured {
+        true => None,
+        false => Some(changes),
+    }
 }
 
-fn new_handlerbars<'a, 'b>() -> anyhow::Result<Handlebars<'b>> {
-    debug!("Creating Handlebars instance...");
+fn git_output(cmd: &mut Command, context: String) -> anyhow::Result<()> {
+    let output = cmd.output().context(context.clone())?;
+    trace!(
+        "Git output -> <blue>{}",
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
 
-    let mut handlebars = Handlebars::new();
+    if !output.status.success() {
+        return Err(SyncError::GitFailure {
+            action: context,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
 
-    handlebars.set_strict_mode(true); // Report missing variables as errors
-    handlebars.register_escape_fn(handlebars::no_escape); // Disable HTML escaping
+    Ok(())
+}
 
-    Ok(handlebars)
+/// Runs a git command that talks to the remote, retrying with backoff when it fails with a network error.
+fn git_retry(conf: &EnvConf, cmd: &mut Command, context: &str) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match git_output(cmd, context.to_string()) {
+            Err(err) if attempt < conf.git_retries && is_transient_git_error(&err) => {
+                let delay = GIT_RETRY_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Retrying in {}s ({}/{}) -> {:#}",
+                    delay.as_secs(),
+                    attempt,
+                    conf.git_retries,
+                    err
+                );
+                sleep(delay);
+            }
+            result => return result,
+        }
+    }
 }
 
-fn fix_permissions(path: &Path, conf: &EnvConf) -> anyhow::Result<()> {
-    if path.is_symlink() {
-        return Ok(());
+fn is_transient_git_error(err: &anyhow::Error) -> bool {
+    let message = format!("{:#}", err).to_lowercase();
+    TRANSIENT_GIT_ERRORS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+fn git_head(repo_dir: &Path) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_dir)
+        .arg("rev-parse")
+        .arg("HEAD")
+        .output()
+        .context("Get repository HEAD")?;
+
+    if !output.status.success() {
+        return Err(SyncError::GitFailure {
+            action: String::from("Failed to get repository HEAD"),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
     }
 
-    let permission = if path.is_dir() {
-        Permissions::from_mode(0o755)
-    } else {
-        Permissions::from_mode(0o644)
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Gets the variables describing the checked out commit, so templates can record where they were synced from.
+fn git_metadata(repo_dir: &Path) -> anyhow::Result<Map<String, Value>> {
+    let git = |args: &[&str]| -> anyhow::Result<String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo_dir)
+            .args(args)
+            .output()
+            .context("Run git")?;
+
+        if !output.status.success() {
+            return Err(SyncError::GitFailure {
+                action: String::from("Failed to read commit metadata"),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }
+            .into());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
     };
 
-    set_permissions(path, permission).context("Set permissions")?;
+    let log = git(&["log", "-1", "--format=%H%n%h%n%cI%n%an%n%ae"])?;
+    let mut fields = log.lines();
+    let mut metadata = Map::new();
+    for name in [
+        "git_commit",
+        "git_commit_short",
+        "git_commit_time",
+        "git_author",
+        "git_author_email",
+    ] {
+        let value = fields.next().unwrap_or_default();
+        metadata.insert(name.to_string(), Value::String(value.to_string()));
+    }
 
-    let owner = conf
-        .get_env("UID")
-        .map(|uid| file_owner::Owner::from(uid.parse::<u32>().unwrap()))
-        .or_else(|| {
-            conf.get_env("USER")
-                .map(|user| file_owner::Owner::from_name(&user).unwrap())
-        })
-        .context("Getting UID or USER environment variable")?;
-
-    let group = conf
-        .get_env("GID")
-        .map(|gid| file_owner::Group::from(gid.parse::<u32>().unwrap()))
-        .or_else(|| {
-            conf.get_env("GROUP")
-                .map(|group| file_owner::Group::from_name(&group).unwrap())
-        })
-        .unwrap_or(file_owner::Group::from_gid(owner.id()));
+    // A detached checkout isn't on a branch, which git reports as HEAD.
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch != "HEAD" {
+        metadata.insert(String::from("git_branch"), Value::String(branch));
+    }
+
+    Ok(metadata)
+}
 
-    file_owner::set_owner_group(path, owner, group).context("Setting file owner and group")?;
+/// Gets the commit the source is at, a local source doesn't have one.
+fn source_revision(conf: &EnvConf) -> anyhow::Result<Option<String>> {
+    match conf.local_source {
+        true => Ok(None),
+        false => git_head(&conf.repo_dir).map(Some),
+    }
+}
+
+fn sync_repository(conf: &EnvConf, repo_dir: &Path) -> anyhow::Result<()> {
+    if conf.local_source {
+        if !repo_dir.is_dir() {
+            return Err(format_err!(
+                "Local source {} isn't a directory",
+                repo_dir.display()
+            ));
+        }
+
+        info!("Using local source {}", repo_dir.display());
+        return Ok(());
+    }
+
+    let repo_url = conf.get_env("SERVER_SYNC_REPO").unwrap();
+    let repo_branch = conf
+        .get_env("SERVER_SYNC_BRANCH")
+        .unwrap_or("master".to_string());
+    let shallow = conf.get_flag("SERVER_SYNC_SHALLOW");
+    let pinned = conf.get_env("SERVER_SYNC_REF");
+
+    if !repo_dir.exists() {
+        info!("Cloning repository {}", &repo_url);
+
+        let mut cmd = Command::new("git");
+        cmd.arg("clone");
+        if shallow {
+            cmd.args(["--depth", "1", "--single-branch", "--branch"])
+                .arg(&repo_branch);
+        }
+
+        cmd.arg(&repo_url).arg(repo_dir);
+        git_retry(conf, &mut cmd, "Clone repository")?;
+    } else if pinned.is_some() {
+        info!("Fetching repository {}", &repo_url);
+
+        // A pinned checkout is detached, so there's no branch to pull into.
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(repo_dir)
+            .args(["fetch", "--tags", "origin"]);
+        if shallow {
+            cmd.args(["--depth", "1"]);
+        }
+
+        git_retry(conf, &mut cmd, "Fetch repository")?;
+    } else {
+        info!("Updating repository {}", &repo_url);
+
+        // A previously pinned checkout is detached, so go back to the branch to pull into it.
+        let detached = !Command::new("git")
+            .arg("-C")
+            .arg(repo_dir)
+            .args(["symbolic-ref", "--quiet", "HEAD"])
+            .output()
+            .context("Get checked out branch")?
+            .status
+            .success();
+        if detached {
+            let mut cmd = Command::new("git");
+            cmd.arg("-C")
+                .arg(repo_dir)
+                .arg("checkout")
+                .arg(&repo_branch);
+            git_output(&mut cmd, "Checkout branch".to_string())?;
+        }
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(repo_dir).arg("pull");
+        if shallow {
+            cmd.args(["--depth", "1"]);
+        }
+
+        git_retry(conf, &mut cmd, "Update repository")?;
+    }
+
+    let target = pinned.as_ref().unwrap_or(&repo_branch);
+    info!("Checking out {}", target);
+
+    let checkout = || match &pinned {
+        Some(pinned) => {
+            let commit = resolve_ref(repo_dir, pinned)?;
+            let mut cmd = Command::new("git");
+            cmd.arg("-C")
+                .arg(repo_dir)
+                .args(["checkout", "--detach"])
+                .arg(&commit);
+
+            git_output(&mut cmd, "Checkout ref".to_string())
+        }
+        None => {
+            let mut cmd = Command::new("git");
+            cmd.arg("-C")
+                .arg(repo_dir)
+                .arg("checkout")
+                .arg(&repo_branch);
+
+            git_output(&mut cmd, "Checkout branch".to_string())
+        }
+    };
+
+    // A shallow clone only has the branch it was cloned with, so fetch the full history to find the others.
+    if let Err(err) = checkout() {
+        if !repo_dir.join(".git/shallow").exists() {
+            return Err(err);
+        }
+
+        warn!(
+            "{} isn't in the shallow clone, fetching the full repository",
+            target
+        );
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(repo_dir)
+            .args(["remote", "set-branches", "origin", "*"]);
+        git_output(&mut cmd, "Track all remote branches".to_string())?;
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(repo_dir).args(["fetch", "--unshallow"]);
+        git_retry(conf, &mut cmd, "Unshallow repository")?;
+
+        checkout()?;
+    }
+
+    if conf.get_flag("SERVER_SYNC_SUBMODULES") {
+        info!("Updating submodules");
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(repo_dir)
+            .args(["submodule", "update", "--init", "--recursive"]);
+        if conf.jobs > 0 {
+            cmd.arg("--jobs").arg(conf.jobs.to_string());
+        }
+        if shallow {
+            cmd.args(["--depth", "1"]);
+        }
+
+        git_retry(conf, &mut cmd, "Update submodules")?;
+    }
+
+    Ok(())
+}
+
+/// Resolves a pinned ref to the commit it points to after a fetch.
+///
+/// Branches are looked up on the remote first so a pinned branch is at its latest fetched commit,
+/// rather than wherever the local branch was left.
+fn resolve_ref(repo_dir: &Path, pinned: &str) -> anyhow::Result<String> {
+    let candidates = [
+        format!("refs/remotes/origin/{}", pinned),
+        pinned.to_string(),
+    ];
+
+    for candidate in candidates {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo_dir)
+            .args(["rev-parse", "--verify", "--quiet"])
+            .arg(format!("{}^{{commit}}", candidate))
+            .output()
+            .context("Resolve ref")?;
+
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        }
+    }
+
+    Err(format_err!(
+        "Ref {} isn't a commit, tag or branch in the repository",
+        pinned
+    ))
+}
+
+/// Lists the files of a context to sync, skipping ignored files and manifests.
+///
+/// Files of included contexts are listed too, unless a later layer has a file at the same path.
+fn source_files(context: &ServerContext, conf: &EnvConf) -> Vec<PathBuf> {
+    let mut files = BTreeMap::new();
+    for root in context.layers() {
+        for source in layer_files(context, conf, root) {
+            let relative_path = source.strip_prefix(root).unwrap().to_path_buf();
+            files.insert(relative_path, source);
+        }
+    }
+
+    files.into_values().collect()
+}
+
+/// Lists the files under one layer of a context, the context's own ignore rules apply to every layer.
+fn layer_files(context: &ServerContext, conf: &EnvConf, root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .same_file_system(true)
+        .follow_links(conf.get_flag("SERVER_SYNC_FOLLOW_SYMLINKS"))
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            let relative_path = e.path().strip_prefix(root).unwrap();
+            // Submodules have a .git file pointing at their repository.
+            if e.depth() > 0 && e.file_name() == ".git" {
+                return false;
+            }
+
+            let is_dir = e.file_type().is_dir();
+            if e.depth() == 0 {
+                return true;
+            }
+
+            if context.ignore.is_ignored(relative_path, is_dir) {
+                debug!("Ignoring {}", relative_path.display());
+                return false;
+            }
+
+            if context.export_ignore.is_ignored(relative_path, is_dir) {
+                debug!("Ignoring {} marked export-ignore", relative_path.display());
+                return false;
+            }
+
+            true
+        })
+        .filter(|e| e.is_ok())
+        .filter(|e| {
+            let file_type = e.as_ref().unwrap().file_type();
+            file_type.is_file() || file_type.is_symlink()
+        })
+        .map(|e| e.unwrap().into_path())
+        .filter(|path| !context.is_manifest(path))
+        .collect()
+}
+
+/// Renders every context into the staging directory as it would be written,
+/// so the pre sync hook can validate the output before anything touches the destination.
+fn stage_contexts(handlebars: &Handlebars, conf: &EnvConf, staging: &Path) -> anyhow::Result<()> {
+    for context in conf.get_contexts() {
+        if !context.source_root.is_dir() {
+            continue;
+        }
+
+        stage_context(
+            handlebars,
+            context,
+            conf,
+            &staging.join(&context.name),
+            false,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Renders the files of a context into a directory standing in for its destination, optionally only
+/// the selected files, returning each source with the path it's staged at and whether it was copied as is.
+fn stage_context(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    staging: &Path,
+    selected_only: bool,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf, bool)>> {
+    // A staged context is delivered whole, so an export that fails fails the context.
+    let mut export_errors = vec![];
+    let exports =
+        render_exports(handlebars, context, conf, &mut export_errors).context("Render exports")?;
+    if let Some(err) = export_errors.into_iter().next() {
+        return Err(err.context("Render exports"));
+    }
+    let mut staged_files = vec![];
+
+    for source in source_files(context, conf) {
+        let relative_path = context.relative_path(&source)?;
+        if selected_only && !conf.is_selected(relative_path) {
+            continue;
+        }
+
+        let target = file_target(handlebars, context, conf, &exports, &source)?;
+        let staged = staging.join(&target);
+        // Only made once something is staged, so a skipped file doesn't leave an empty directory to deliver.
+        let create_parent = || {
+            create_dir_all(staged.parent().context("Get staged parent folder")?)
+                .context("Create staged directory")
+        };
+
+        if source.is_symlink() && !conf.get_flag("SERVER_SYNC_FOLLOW_SYMLINKS") {
+            create_parent()?;
+            symlinks::link(&read_link(&source).context("Read symlink")?, &staged)
+                .with_context(|| format!("Stage symlink {}", source.display()))?;
+            staged_files.push((source, target, false));
+            continue;
+        }
+
+        let contents = read_template(context, conf, &source, relative_path)?;
+        let copied = contents.is_none();
+
+        match contents {
+            None => {
+                create_parent()?;
+                copy(&source, &staged)
+                    .with_context(|| format!("Stage file {}", source.display()))?;
+            }
+            Some(contents) => {
+                let destination_path = context.get_destination(conf).join(&target);
+                let encoding = context.get_encoding(relative_path);
+                let (front_matter, driver, contents) =
+                    prepare_template(context, conf, relative_path, &contents)?;
+                let rendered = match render_conditional(
+                    handlebars,
+                    context,
+                    conf,
+                    &exports,
+                    relative_path,
+                    front_matter.skip_if.as_deref(),
+                    &contents,
+                )? {
+                    Some(rendered) => rendered,
+                    None => continue,
+                };
+                if conf.validate {
+                    merger::validate(relative_path, &rendered)?;
+                }
+
+                let merged = merge_existing(
+                    context,
+                    &destination_path,
+                    conf,
+                    driver,
+                    &encoding,
+                    normalize_line_endings(conf, &rendered),
+                )?;
+                let merged = normalize_line_endings(conf, &merged);
+
+                create_parent()?;
+                write(&staged, encoding.encode(&destination_path, &merged)?)
+                    .with_context(|| format!("Stage file {}", source.display()))?;
+            }
+        }
+
+        staged_files.push((source, target, copied));
+    }
+
+    Ok(staged_files)
+}
+
+/// Syncs a context by rendering it into a staging directory that's delivered to the destination with rsync,
+/// returning the files synced and the ones that changed like walking the context does.
+fn rsync_context(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    report: &mut SyncReport,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let staging = env::temp_dir().join(format!(
+        "server-sync-rsync-{}-{}",
+        std::process::id(),
+        context.name
+    ));
+    let result = stage_context(handlebars, context, conf, &staging, true)
+        .context("Stage rendered files")
+        .and_then(|staged| deliver_staged(context, conf, &staging, staged, report));
+    let _ = remove_dir_all(&staging);
+
+    result
+}
+
+fn deliver_staged(
+    context: &ServerContext,
+    conf: &EnvConf,
+    staging: &Path,
+    staged: Vec<(PathBuf, PathBuf, bool)>,
+    report: &mut SyncReport,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let destination_root = context.get_destination(conf);
+
+    // rsync replaces a symlink at the destination with the file, which is refused like the built-in writer does.
+    for (_, target, _) in &staged {
+        let destination = destination_root.join(target);
+        if !staging.join(target).is_symlink() {
+            symlinks::write_target(&destination, false)?;
+        }
+        if let Some(parent) = destination.parent() {
+            ensure_ancestors(parent, destination_root, conf)?;
+        }
+    }
+
+    let delivered = rsync::deliver(staging, destination_root, conf)
+        .map(|delivered| delivered.into_iter().collect::<BTreeMap<_, _>>())?;
+
+    let mut changed = vec![];
+    for (source, target, copied) in &staged {
+        let destination = destination_root.join(target);
+        let change = match delivered.get(target) {
+            None => FileChange::Unchanged,
+            Some(created) => {
+                let relative_path = context.relative_path(source)?;
+                let front_matter = read_front_matter(context, conf, source, relative_path)?;
+                let (mode, ownership) =
+                    file_permissions(context, conf, relative_path, &front_matter)?;
+                fix_owned_permissions(&destination, mode, ownership, conf)?;
+                restore_security_context(&destination, conf)?;
+                changed.push(target.to_owned());
+
+                let bytes = symlink_metadata(&destination)
+                    .context("Read delivered file")?
+                    .len();
+                match (created, conf.backs_up_files()) {
+                    (true, _) => FileChange::Created(bytes),
+                    (false, true) => {
+                        remove_old_backups(&destination, conf)?;
+                        FileChange::Updated(bytes)
+                    }
+                    (false, false) => FileChange::Overwritten(bytes),
+                }
+            }
+        };
+        report.record(&destination, change, *copied);
+    }
+
+    let synced = staged.into_iter().map(|(_, target, _)| target).collect();
+
+    Ok((synced, changed))
+}
+
+/// Syncs every file of a context, returning the number of files
+/// and the paths relative to the destination of the files that changed.
+fn walk_directory(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    cache: &FileCache,
+    report: &mut SyncReport,
+    errors: &mut Vec<anyhow::Error>,
+    only: Option<&BTreeSet<PathBuf>>,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let exports = render_exports(handlebars, context, conf, errors).context("Render exports")?;
+
+    // Each source file paired with the path it's synced to relative to the destination.
+    let mut targets = vec![];
+    for entry in source_files(context, conf) {
+        match file_target(handlebars, context, conf, &exports, &entry) {
+            Ok(target) => targets.push((entry, target)),
+            Err(err) => collect_error(
+                conf,
+                errors,
+                Err(err.context(format!("Sync file {}", entry.display()))),
+            )?,
+        }
+    }
+
+    // Files that aren't selected are still managed, they just aren't looked at this time.
+    let (pending, skipped): (Vec<_>, Vec<_>) = targets.iter().partition(|(entry, _)| {
+        context.relative_path(entry).is_ok_and(|relative_path| {
+            conf.is_selected(relative_path) && only.is_none_or(|only| only.contains(relative_path))
+        })
+    });
+    for (_, target) in skipped {
+        cache.keep(&context.get_destination(conf).join(target));
+    }
+
+    // Once an error would abort the sync no more files are written,
+    // files already being synced on other threads still finish.
+    let aborted = AtomicBool::new(false);
+    let progress = progress::context_bar(conf, &context.name, pending.len());
+    let results = pending
+        .par_iter()
+        .map(|(entry, target)| {
+            if aborted.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let result = sync_entry(handlebars, context, conf, &exports, cache, entry, target);
+            if result.as_ref().is_err_and(|err| !is_collected(conf, err)) {
+                aborted.store(true, Ordering::SeqCst);
+            }
+            progress.set_message(target.display().to_string());
+            progress.inc(1);
+            Some(result)
+        })
+        .collect::<Vec<_>>();
+    progress.finish_and_clear();
+
+    let mut changed = vec![];
+    for ((_, target), result) in pending.iter().zip(results) {
+        match result {
+            // Skipped after the sync was aborted, the error is further along.
+            None => {}
+            Some(Ok((change, copied))) => {
+                if !matches!(change, FileChange::Unchanged | FileChange::Skipped) {
+                    changed.push(target.to_owned());
+                }
+                let destination = context.get_destination(conf).join(target);
+                report.record(&destination, change, copied);
+            }
+            Some(Err(err)) => collect_error(conf, errors, Err(err))?,
+        }
+    }
+
+    let synced = targets.into_iter().map(|(_, target)| target).collect();
+
+    Ok((synced, changed))
+}
+
+/// Removes the files a previous sync managed for the context whose source has since been deleted.
+///
+/// Only paths from the managed list of the last sync are candidates, so anything placed by hand or
+/// created by the server is never touched. A path is kept while another context sharing the destination
+/// still has a source for it, and the kept paths are returned so they stay managed.
+fn prune_context(
+    context: &ServerContext,
+    conf: &EnvConf,
+    stale: &[PathBuf],
+    report: &mut SyncReport,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let destination_root = context.get_destination(conf);
+    let mut kept = vec![];
+
+    for relative_path in stale {
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            warn!(
+                "Not pruning {} as it's outside of the destination",
+                relative_path.display()
+            );
+            continue;
+        }
+
+        let has_source = conf.get_contexts().iter().any(|other| {
+            other.get_destination(conf) == destination_root
+                && other
+                    .layers()
+                    .any(|root| root.join(relative_path).symlink_metadata().is_ok())
+        });
+        if has_source {
+            kept.push(relative_path.to_owned());
+            continue;
+        }
+
+        let path = destination_root.join(relative_path);
+        if path.symlink_metadata().is_err() {
+            continue;
+        }
+
+        if conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+            info!("Would prune {}", path.display());
+        } else {
+            info!("Pruning {}", path.display());
+            remove_destination(&path, conf).with_context(|| format!("Prune {}", path.display()))?;
+        }
+
+        report.record_pruned(&path, conf.backs_up_files());
+    }
+
+    Ok(kept)
+}
+
+/// Removes a file at the destination, backing it up first unless backups are disabled.
+fn remove_destination(path: &Path, conf: &EnvConf) -> anyhow::Result<()> {
+    if !conf.backs_up_files() {
+        return remove_file(path).context("Remove file");
+    }
+
+    let backup_path = conf.backup_mode.backup_path(path, &conf.backup_suffix)?;
+    rename(path, backup_path).context("Back up file")?;
+    remove_old_backups(path, conf)
+}
+
+/// Handles a file that isn't written as its `server-sync-if` condition is false,
+/// an existing copy is left alone unless `--remove-skipped` is set.
+fn skip_file(destination: &Path, conf: &EnvConf) -> anyhow::Result<FileChange> {
+    if !conf.get_flag("SERVER_SYNC_REMOVE_SKIPPED") || destination.symlink_metadata().is_err() {
+        return Ok(FileChange::Skipped);
+    }
+
+    if conf.get_flag("SERVER_SYNC_DIFF_ONLY") {
+        info!("Would remove skipped file {}", destination.display());
+    } else {
+        info!("Removing skipped file {}", destination.display());
+        remove_destination(destination, conf)?;
+    }
+
+    Ok(FileChange::Removed {
+        backed_up: conf.backs_up_files(),
+    })
+}
+
+/// Syncs a single source file, files that aren't utf8, use the binary driver or are marked as raw are copied as is.
+///
+/// Returns what happened to the destination and whether the file was copied.
+fn sync_entry(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    cache: &FileCache,
+    source: &Path,
+    target: &Path,
+) -> anyhow::Result<(FileChange, bool)> {
+    let relative_path = context.relative_path(source)?;
+    let destination_path = context.get_destination(conf).join(target);
+
+    trace!("Processing file {}", relative_path.display());
+
+    if source.is_symlink() && !conf.get_flag("SERVER_SYNC_FOLLOW_SYMLINKS") {
+        return sync_symlink(source, &destination_path, context, conf)
+            .map(|change| (change, true))
+            .with_context(|| format!("Link file {}", source.display()));
+    }
+
+    let contents = read_template(context, conf, source, relative_path)?;
+
+    match contents {
+        None => copy_file(
+            source,
+            &destination_path,
+            context.get_destination(conf),
+            conf,
+            cache,
+        )
+        .map(|change| (change, true))
+        .with_context(|| format!("Copy file {}", source.display())),
+        Some(contents) => {
+            let (front_matter, driver, contents) =
+                prepare_template(context, conf, relative_path, &contents)
+                    .with_context(|| format!("Sync file {}", source.display()))?;
+            let options = write_options(context, conf, relative_path, &front_matter, driver)
+                .with_context(|| format!("Sync file {}", source.display()))?;
+            render_conditional(
+                handlebars,
+                context,
+                conf,
+                exports,
+                relative_path,
+                front_matter.skip_if.as_deref(),
+                &contents,
+            )
+            .and_then(|rendered| match rendered {
+                Some(rendered) => sync_file(
+                    context,
+                    conf,
+                    cache,
+                    relative_path,
+                    &destination_path,
+                    &options,
+                    &rendered,
+                ),
+                None => skip_file(&destination_path, conf),
+            })
+            .map(|change| (change, false))
+            .with_context(|| format!("Sync file {}", source.display()))
+        }
+    }
+}
+
+fn collect_error(
+    conf: &EnvConf,
+    errors: &mut Vec<anyhow::Error>,
+    result: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match result {
+        Err(err) if is_collected(conf, &err) => {
+            error!("{:#}", err);
+            errors.push(err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Whether an error is collected to be reported at the end instead of aborting the sync,
+/// a broken template, document or unreadable source only affects its own file
+/// so keep going unless told to fail fast.
+fn is_collected(conf: &EnvConf, err: &anyhow::Error) -> bool {
+    !conf.get_flag("SERVER_SYNC_FAIL_FAST")
+        && (conf.get_flag("SERVER_SYNC_KEEP_GOING")
+            || err.chain().any(|cause| {
+                cause.is::<RenderError>()
+                    || cause.is::<DocumentError>()
+                    || matches!(
+                        cause.downcast_ref::<SyncError>(),
+                        Some(SyncError::IoFailure { .. })
+                    )
+            }))
+}
+
+/// Renders the files listed in the exports manifest in order, collecting their output as variables.
+///
+/// Each export can reference the exports declared before it.
+fn render_exports(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    errors: &mut Vec<anyhow::Error>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut exports = BTreeMap::new();
+
+    for (name, relative_path) in &context.exports {
+        match render_export(handlebars, context, conf, &exports, name, relative_path) {
+            Ok(rendered) => {
+                exports.insert(name.to_owned(), rendered);
+            }
+            Err(err) => collect_error(conf, errors, Err(err))?,
+        }
+    }
+
+    Ok(exports)
+}
+
+fn render_export(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    name: &str,
+    relative_path: &Path,
+) -> anyhow::Result<String> {
+    let source = context.source_path(relative_path);
+    trace!("Exporting {} as {}", relative_path.display(), name);
+
+    let contents = get_contents(&source)?
+        .with_context(|| format!("Export {} isn't a utf8 file", source.display()))?;
+    let (_, contents) = FrontMatter::split(&contents)
+        .with_context(|| format!("Read front matter of export {}", name))?;
+    let rendered = render_entry(
+        handlebars,
+        context,
+        conf,
+        exports,
+        relative_path,
+        &context.template_source(relative_path, contents),
+    )
+    .with_context(|| format!("Render export {}", name))?;
+
+    Ok(rendered.trim().to_string())
+}
+
+/// Writes the rendered contents of a template to the destination, merging them with what's there.
+fn sync_file(
+    context: &ServerContext,
+    conf: &EnvConf,
+    cache: &FileCache,
+    relative_path: &Path,
+    destination_path: &Path,
+    options: &WriteOptions,
+    rendered: &str,
+) -> anyhow::Result<FileChange> {
+    let rendered = normalize_line_endings(conf, rendered);
+    if conf.validate {
+        merger::validate(relative_path, &rendered)?;
+    }
+    let encoding = context.get_encoding(relative_path);
+    let WriteOptions {
+        driver,
+        mode,
+        ownership,
+    } = *options;
+
+    let hash = content_hash(conf, &rendered, &encoding, options);
+    if cache.is_fresh(destination_path, hash) {
+        debug!(
+            "File {} is unchanged since the last sync",
+            destination_path.display()
+        );
+        return Ok(FileChange::Unchanged);
+    }
+
+    let parent = destination_path.parent().expect("File was at / level???");
+
+    trace!(
+        "Templating {} to {}",
+        &relative_path.display(),
+        &destination_path.display()
+    );
+
+    let diff_only = conf.get_flag("SERVER_SYNC_DIFF_ONLY");
+    if !diff_only {
+        ensure_ancestors(parent, context.get_destination(conf), conf)?;
+    }
+    let destination_path = &symlinks::write_target(
+        destination_path,
+        conf.get_flag("SERVER_SYNC_FOLLOW_DEST_SYMLINKS"),
+    )?;
+
+    let rendered = merge_existing(context, destination_path, conf, driver, &encoding, rendered)?;
+    let rendered = normalize_line_endings(conf, &rendered);
+
+    let change = if check_existing(destination_path, &rendered, &encoding, conf)? {
+        debug!("File {} is up to date", destination_path.display());
+        if !diff_only {
+            fix_owned_permissions(destination_path, mode, ownership, conf)?;
+        }
+        FileChange::Unchanged
+    } else if diff_only {
+        return Ok(skipped_write(
+            destination_path,
+            encoding.encode(destination_path, &rendered)?.len() as u64,
+        ));
+    } else {
+        let change = backup_and_write(
+            destination_path,
+            &encoding.encode(destination_path, &rendered)?,
+            conf,
+        )?;
+        fix_owned_permissions(destination_path, mode, ownership, conf)?;
+        restore_security_context(destination_path, conf)?;
+        throttle(conf);
+        change
+    };
+
+    cache.record(destination_path, hash);
+    Ok(change)
+}
+
+/// Splits a template into its front matter, merge driver and the contents to render.
+///
+/// A merge driver in the front matter wins, otherwise a template starting with the overwrite marker
+/// replaces the destination. Neither the front matter nor the marker are rendered.
+fn prepare_template<'c>(
+    context: &ServerContext,
+    conf: &EnvConf,
+    relative_path: &Path,
+    contents: &'c str,
+) -> anyhow::Result<(FrontMatter, MergeDriver, Cow<'c, str>)> {
+    let (front_matter, contents) = FrontMatter::split(contents).context("Read front matter")?;
+    let (driver, contents) = match merger::strip_overwrite_marker(contents) {
+        Some(contents) => (MergeDriver::TextOverwrite, contents),
+        None => (context.get_merge_driver(relative_path, conf), contents),
+    };
+
+    let driver = front_matter.merge.unwrap_or(driver);
+    let contents = context.template_source(relative_path, contents);
+    Ok((front_matter, driver, contents))
+}
+
+/// Reads only the front matter of a source file, for deciding where it's synced before it's rendered.
+///
+/// Files copied as they are and symlinks don't have any.
+fn read_front_matter(
+    context: &ServerContext,
+    conf: &EnvConf,
+    source: &Path,
+    relative_path: &Path,
+) -> anyhow::Result<FrontMatter> {
+    if (source.is_symlink() && !conf.get_flag("SERVER_SYNC_FOLLOW_SYMLINKS"))
+        || context.is_raw_copy(relative_path)
+    {
+        return Ok(FrontMatter::default());
+    }
+
+    // Most files don't start with the delimiter, so they aren't read in full twice.
+    let mut start = [0; 3];
+    let starts_with_delimiter = File::open(source)
+        .and_then(|mut file| file.read_exact(&mut start))
+        .is_ok_and(|_| &start == b"---");
+    if !starts_with_delimiter {
+        return Ok(FrontMatter::default());
+    }
+
+    match get_contents(source)? {
+        Some(contents) => FrontMatter::split(&contents)
+            .map(|(front_matter, _)| front_matter)
+            .with_context(|| format!("Read front matter of {}", source.display())),
+        None => Ok(FrontMatter::default()),
+    }
+}
+
+/// Renders the path a source file is synced to, its front matter can move it elsewhere in the destination.
+fn file_target(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    source: &Path,
+) -> anyhow::Result<PathBuf> {
+    let relative_path = context.relative_path(source)?;
+    match read_front_matter(context, conf, source, relative_path)?.destination {
+        Some(destination) => {
+            render_path(handlebars, context, conf, exports, Path::new(&destination))
+        }
+        None => render_path(handlebars, context, conf, exports, relative_path),
+    }
+}
+
+/// How a rendered template is written to its destination.
+#[derive(Clone, Copy)]
+struct WriteOptions {
+    driver: MergeDriver,
+    mode: u32,
+    ownership: Option<(u32, u32)>,
+}
+
+/// Gets how a template is written with the merge driver it was prepared with.
+fn write_options(
+    context: &ServerContext,
+    conf: &EnvConf,
+    relative_path: &Path,
+    front_matter: &FrontMatter,
+    driver: MergeDriver,
+) -> anyhow::Result<WriteOptions> {
+    let (mode, ownership) = file_permissions(context, conf, relative_path, front_matter)?;
+
+    Ok(WriteOptions {
+        driver,
+        mode,
+        ownership,
+    })
+}
+
+/// Gets the mode and owner of a synced file, its front matter overrides the configured ones.
+fn file_permissions(
+    context: &ServerContext,
+    conf: &EnvConf,
+    relative_path: &Path,
+    front_matter: &FrontMatter,
+) -> anyhow::Result<(u32, Option<(u32, u32)>)> {
+    let mode = match front_matter.mode {
+        Some(mode) => mode,
+        None => conf.file_mode(&context.source_path(relative_path), relative_path)?,
+    };
+    let ownership = conf
+        .file_ownership(front_matter.owner.as_deref(), front_matter.group.as_deref())
+        .context("Resolve front matter owner")?;
+
+    Ok((mode, ownership))
+}
+
+/// Normalizes the line endings of rendered contents, the merged output is normalized again
+/// as merge drivers write their own line endings.
+fn normalize_line_endings(conf: &EnvConf, contents: &str) -> String {
+    conf.line_ending.normalize(
+        contents,
+        conf.get_flag("SERVER_SYNC_ENSURE_TRAILING_NEWLINE"),
+    )
+}
+
+/// Merges the rendered contents into the file at the destination with the merge driver,
+/// the rendered contents are used as is when there's nothing to merge with.
+fn merge_existing(
+    context: &ServerContext,
+    destination_path: &Path,
+    conf: &EnvConf,
+    driver: MergeDriver,
+    encoding: &OutputEncoding,
+    rendered: String,
+) -> anyhow::Result<String> {
+    let merge_path = context.merge_path(conf, destination_path);
+    // A file that isn't staged yet is merged with the live file it will replace.
+    let existing_path = match merge_path.exists() {
+        true => merge_path,
+        false => conf.live_path(&merge_path),
+    };
+    if driver == MergeDriver::TextOverwrite || !existing_path.exists() {
+        return Ok(rendered);
+    }
+
+    let existing = encoding
+        .decode(&read(&existing_path).context("Read existing file")?)
+        .context("Existing file can't be decoded for merging")?;
+
+    driver
+        .merge(&existing, &rendered)
+        .with_context(|| format!("Merge with {:?}", driver))
+        .map_err(|source| {
+            SyncError::MergeFailure {
+                file: destination_path.to_owned(),
+                source,
+            }
+            .into()
+        })
+}
+
+/// Hashes everything that decides what a file looks like once synced,
+/// so changing the requested mode or ownership invalidates the cached fingerprint too.
+fn content_hash(
+    conf: &EnvConf,
+    rendered: &str,
+    encoding: &OutputEncoding,
+    options: &WriteOptions,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    rendered.hash(&mut hasher);
+    encoding.hash(&mut hasher);
+    format!("{:?}", options.driver).hash(&mut hasher);
+    options.mode.hash(&mut hasher);
+
+    options.ownership.hash(&mut hasher);
+    conf.line_ending.hash(&mut hasher);
+    conf.get_flag("SERVER_SYNC_IGNORE_WHITESPACE")
+        .hash(&mut hasher);
+
+    hasher.finish()
+}
+
+fn copy_file(
+    source: &Path,
+    dest: &Path,
+    destination_root: &Path,
+    conf: &EnvConf,
+    cache: &FileCache,
+) -> anyhow::Result<FileChange> {
+    let mode = conf.file_mode(
+        source,
+        dest.strip_prefix(destination_root)
+            .context("Get relative path")?,
+    )?;
+
+    let source_hash = file_hash(source).context("Hash source file")?;
+    let hash = copy_hash(conf, source_hash, mode);
+    if cache.is_fresh(dest, hash) {
+        debug!("File {} is unchanged since the last sync", dest.display());
+        return Ok(FileChange::Unchanged);
+    }
+
+    let diff_only = conf.get_flag("SERVER_SYNC_DIFF_ONLY");
+    if !diff_only {
+        ensure_ancestors(
+            dest.parent().context("Get destination parent folder.")?,
+            destination_root,
+            conf,
+        )?;
+    }
+    let dest = &symlinks::write_target(dest, conf.get_flag("SERVER_SYNC_FOLLOW_DEST_SYMLINKS"))?;
+
+    let change =
+        if same_contents(source, source_hash, dest).context("Compare with existing file")? {
+            debug!("File {} is up to date", dest.display());
+            if !diff_only {
+                fix_permissions(dest, mode, conf)?;
+            }
+            FileChange::Unchanged
+        } else if diff_only {
+            info!("Binary file {} differs", dest.display());
+            skipped_write(dest, source.metadata().context("Read source file")?.len())
+        } else {
+            let change = backup_and_copy(source, source_hash, dest, conf)?;
+            fix_permissions(dest, mode, conf)?;
+            restore_security_context(dest, conf)?;
+            throttle(conf);
+            change
+        };
+
+    cache.record(dest, hash);
+
+    Ok(change)
+}
+
+fn sync_symlink(
+    source: &Path,
+    dest: &Path,
+    context: &ServerContext,
+    conf: &EnvConf,
+) -> anyhow::Result<FileChange> {
+    let diff_only = conf.get_flag("SERVER_SYNC_DIFF_ONLY");
+    if !diff_only {
+        ensure_ancestors(
+            dest.parent().context("Get destination parent folder.")?,
+            context.get_destination(conf),
+            conf,
+        )?;
+    }
+
+    symlinks::sync_symlink(source, dest, context.get_destination(conf), diff_only)
+}
+
+/// Reports the change a write would have made in diff only mode, without touching the destination.
+fn skipped_write(destination: &Path, bytes: u64) -> FileChange {
+    debug!("Not writing {} in diff only mode", destination.display());
+
+    match destination.exists() {
+        true => FileChange::Updated(bytes),
+        false => FileChange::Created(bytes),
+    }
+}
+
+/// Hashes the bytes copied for a file along with what's applied to it afterwards.
+fn copy_hash(conf: &EnvConf, source_hash: u64, mode: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source_hash.hash(&mut hasher);
+    mode.hash(&mut hasher);
+    conf.ownership.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Hashes a file in chunks so large files never have to be held in memory.
+fn file_hash(path: &Path) -> std::io::Result<u64> {
+    let mut reader = BufReader::with_capacity(COPY_CHUNK_SIZE, File::open(path)?);
+    let mut hasher = DefaultHasher::new();
+
+    loop {
+        let chunk = reader.fill_buf()?;
+        let length = chunk.len();
+        if length == 0 {
+            return Ok(hasher.finish());
+        }
+
+        hasher.write(chunk);
+        reader.consume(length);
+    }
+}
+
+/// Checks if the destination has the same contents as the source by hash,
+/// only comparing every byte when the hashes match to rule out a collision.
+fn same_contents(source: &Path, source_hash: u64, dest: &Path) -> std::io::Result<bool> {
+    if !dest.exists() || source.metadata()?.len() != dest.metadata()?.len() {
+        return Ok(false);
+    }
+
+    Ok(file_hash(dest)? == source_hash && files_equal(source, dest)?)
+}
+
+/// Compares two files a chunk at a time, stopping at the first difference.
+fn files_equal(a: &Path, b: &Path) -> std::io::Result<bool> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::with_capacity(COPY_CHUNK_SIZE, File::open(a)?);
+    let mut b = BufReader::with_capacity(COPY_CHUNK_SIZE, File::open(b)?);
+
+    loop {
+        let (a_chunk, b_chunk) = (a.fill_buf()?, b.fill_buf()?);
+        let length = a_chunk.len().min(b_chunk.len());
+
+        if length == 0 {
+            return Ok(a_chunk.is_empty() && b_chunk.is_empty());
+        }
+
+        if a_chunk[..length] != b_chunk[..length] {
+            return Ok(false);
+        }
+
+        a.consume(length);
+        b.consume(length);
+    }
+}
+
+fn backup_and_write(
+    destination: &Path,
+    contents: &[u8],
+    conf: &EnvConf,
+) -> anyhow::Result<FileChange> {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+
+    replace_file(destination, conf, hasher.finish(), |file| {
+        file.write_all(contents).context("Write out all bytes")?;
+        Ok(contents.len() as u64)
+    })
+}
+
+/// Streams the source into the destination, so large files are never held in memory.
+fn backup_and_copy(
+    source: &Path,
+    source_hash: u64,
+    destination: &Path,
+    conf: &EnvConf,
+) -> anyhow::Result<FileChange> {
+    replace_file(destination, conf, source_hash, |file| {
+        let mut source = BufReader::with_capacity(
+            COPY_CHUNK_SIZE,
+            File::open(source).context("Open source file")?,
+        );
+        copy_stream(&mut source, file).context("Copy source file")
+    })
+}
+
+/// Writes to a temporary sibling first and renames it into place once it's on disk,
+/// so a crash or full disk never leaves a truncated file at the destination.
+///
+/// `hash` is the [`file_hash`] of the contents, the file is read back and checked against it
+/// when writes are verified.
+fn replace_file(
+    destination: &Path,
+    conf: &EnvConf,
+    hash: u64,
+    write_contents: impl FnOnce(&mut File) -> anyhow::Result<u64>,
+) -> anyhow::Result<FileChange> {
+    let file_name = destination
+        .file_name()
+        .context("Get destination file name")?
+        .to_string_lossy();
+    let temp_path = destination.with_file_name(format!("{}.tmp-{}", file_name, std::process::id()));
+
+    trace!("Writing {}", temp_path.display());
+    let result = File::create(&temp_path)
+        .context("Create temporary file at destination")
+        .and_then(|mut file| {
+            let bytes = write_contents(&mut file)?;
+            file.sync_all().context("Flush temporary file to disk")?;
+            Ok(bytes)
+        });
+
+    let bytes = match result {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            let _ = remove_file(&temp_path);
+            return Err(err);
+        }
+    };
+
+    let existed = destination.exists();
+    let backup = conf.backs_up_files();
+    if existed && backup {
+        trace!("Backing up {}", destination.display());
+        let backup_path = conf
+            .backup_mode
+            .backup_path(destination, &conf.backup_suffix)?;
+        rename(destination, backup_path).context("Rename old file")?;
+        remove_old_backups(destination, conf)?;
+    }
+
+    trace!("Moving {} into place", temp_path.display());
+    rename(&temp_path, destination).context("Rename temporary file into place")?;
+
+    if conf.get_flag("SERVER_SYNC_VERIFY_WRITES") {
+        let written = file_hash(destination).map_err(|source| SyncError::IoFailure {
+            path: destination.to_owned(),
+            source,
+        })?;
+        if written != hash {
+            return Err(format_err!(
+                "{} doesn't contain what was written, it was corrupted or changed by something else",
+                destination.display()
+            ));
+        }
+    }
+
+    Ok(match (existed, backup) {
+        (true, true) => FileChange::Updated(bytes),
+        (true, false) => FileChange::Overwritten(bytes),
+        (false, _) => FileChange::Created(bytes),
+    })
+}
+
+/// Sleeps after a write when throttling is enabled, spreading writes out over time.
+fn throttle(conf: &EnvConf) {
+    if let Some(delay) = conf.write_delay {
+        trace!("Throttling writes for {:?}", delay);
+        sleep(delay);
+    }
+}
+
+/// Runs restorecon on a written path so it gets the SELinux context the policy expects.
+///
+/// This is skipped on systems without SELinux or without restorecon installed.
+fn restore_security_context(path: &Path, conf: &EnvConf) -> anyhow::Result<()> {
+    if !conf.get_flag("SERVER_SYNC_RESTORECON") {
+        return Ok(());
+    }
+
+    if !Path::new("/sys/fs/selinux/enforce").exists() {
+        trace!(
+            "SELinux isn't enabled, skipping restorecon for {}",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let output = match Command::new("restorecon").arg(path).output() {
+        Ok(output) => output,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!(
+                "restorecon isn't installed, skipping security context for {}",
+                path.display()
+            );
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("Run restorecon"),
+    };
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "restorecon failed for {} -> {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Removes the oldest backups of a file past `SERVER_SYNC_MAX_BACKUPS`, after it was just backed up.
+fn remove_old_backups(destination: &Path, conf: &EnvConf) -> anyhow::Result<()> {
+    match conf.max_backups {
+        Some(max) => conf
+            .backup_mode
+            .prune_backups(destination, &conf.backup_suffix, max)
+            .with_context(|| format!("Remove old backups of {}", destination.display())),
+        None => Ok(()),
+    }
+}
+
+fn ensure_ancestors(parent: &Path, destination_root: &Path, conf: &EnvConf) -> anyhow::Result<()> {
+    let ancestors_dirs = parent.ancestors().collect::<Vec<&Path>>();
+
+    for ancestor in ancestors_dirs.iter().rev() {
+        if !ancestor.starts_with(destination_root) {
+            continue;
+        }
+
+        if !ancestor.exists() {
+            // Another file in the same directory may be creating it in parallel.
+            match create_dir(ancestor) {
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                result => result.context("Create ancestor directory")?,
+            }
+            fix_permissions(ancestor, conf.dir_mode, conf)?;
+            restore_security_context(ancestor, conf)?;
+        } else {
+            fix_permissions(ancestor, conf.dir_mode, conf)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a file as utf8, `Ok(None)` means the file was read but isn't utf8.
+fn get_contents<P: AsRef<Path>>(path: P) -> anyhow::Result<Option<String>> {
+    let path = path.as_ref();
+    let mut source = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut source))
+        .map_err(|source| SyncError::IoFailure {
+            path: path.to_owned(),
+            source,
+        })?;
+
+    match simdutf8::basic::from_utf8(&source) {
+        Ok(contents) => Ok(Some(contents.to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a file to render, `None` when it's copied byte for byte instead.
+///
+/// Files that aren't utf8 are copied as they are, unless strict utf8 is set and the extension says it's a template.
+fn read_template(
+    context: &ServerContext,
+    conf: &EnvConf,
+    source: &Path,
+    relative_path: &Path,
+) -> anyhow::Result<Option<String>> {
+    if context.get_merge_driver(relative_path, conf) == MergeDriver::Binary
+        || context.is_raw_copy(relative_path)
+    {
+        return Ok(None);
+    }
+
+    let contents = get_contents(source)?;
+    let template_extension = relative_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| TEMPLATE_EXTENSIONS.contains(&extension.as_str()));
+    if contents.is_none() && template_extension && conf.get_flag("SERVER_SYNC_STRICT_UTF8") {
+        return Err(format_err!(
+            "Template {} isn't utf8, fix its encoding or add it to {} to copy it as is",
+            relative_path.display(),
+            RAW_COPY_FILE
+        ));
+    }
+
+    Ok(contents)
+}
+
+/// Renders the path a file is synced to relative to the destination, so `{{ }}` in its segments
+/// are substituted with the same variables as the contents.
+///
+/// A rendered path that isn't a plain relative path would escape the destination and is an error.
+fn render_path(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    relative_path: &Path,
+) -> anyhow::Result<PathBuf> {
+    let raw = relative_path.to_string_lossy();
+    if !raw.contains("{{") {
+        return Ok(relative_path.to_path_buf());
+    }
+
+    let rendered = PathBuf::from(render_entry(
+        handlebars,
+        context,
+        conf,
+        exports,
+        relative_path,
+        &raw,
+    )?);
+    if rendered.as_os_str().is_empty()
+        || !rendered
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format_err!(
+            "Rendered path {} escapes the destination",
+            rendered.display()
+        ));
+    }
+
+    trace!(
+        "Rendered path {} as {}",
+        relative_path.display(),
+        rendered.display()
+    );
+    Ok(rendered)
+}
+
+/// Renders a single template against the shared registry.
+///
+/// The template is rendered directly instead of being registered, so the registry
+/// is never mutated and can be shared between threads.
+fn render_entry(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    relative_path: &Path,
+    contents: &str,
+) -> anyhow::Result<String> {
+    let data = template_data(context, conf, exports);
+    if conf.verbosity() >= render_trace::RENDER_TRACE_VERBOSITY {
+        render_trace::trace_bindings(relative_path, contents, &data);
+    }
+
+    handlebars
+        .render_template(contents, &data)
+        .map_err(|source| {
+            SyncError::RenderFailure {
+                file: relative_path.to_owned(),
+                source,
+            }
+            .into()
+        })
+}
+
+/// Renders a template, one wrapped whole in `server-sync-if` is only rendered when its condition
+/// is true and one with a `skip_if` condition in its front matter only when it's false.
+/// `None` is returned when the template is skipped.
+fn render_conditional(
+    handlebars: &Handlebars,
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+    relative_path: &Path,
+    skip_if: Option<&str>,
+    contents: &str,
+) -> anyhow::Result<Option<String>> {
+    let holds = |condition| {
+        render_entry(
+            handlebars,
+            context,
+            conf,
+            exports,
+            relative_path,
+            &condition_template(condition),
+        )
+        .map(|rendered| !rendered.is_empty())
+    };
+
+    if let Some(condition) = skip_if {
+        if holds(condition)? {
+            debug!(
+                "Skipping {} as its skip_if condition is true",
+                relative_path.display()
+            );
+            return Ok(None);
+        }
+    }
+
+    let contents = match Conditional::find(contents) {
+        Some(conditional) if !holds(conditional.condition)? => {
+            debug!(
+                "Skipping {} as its {} condition is false",
+                relative_path.display(),
+                CONDITION_HELPER
+            );
+            return Ok(None);
+        }
+        Some(conditional) => conditional.body,
+        None => contents,
+    };
+
+    render_entry(handlebars, context, conf, exports, relative_path, contents).map(Some)
+}
+
+/// Gets the variables templates of a context are rendered with.
+fn template_data(
+    context: &ServerContext,
+    conf: &EnvConf,
+    exports: &BTreeMap<String, String>,
+) -> Map<String, Value> {
+    let mut data = conf.get_template_data();
+    data.extend(context.vars.clone());
+    data.extend(
+        exports
+            .iter()
+            .map(|(name, value)| (name.to_owned(), Value::String(value.to_owned()))),
+    );
+    if !conf.get_flag("SERVER_SYNC_NO_SERVER_NAME") {
+        data.insert(
+            String::from("server_name"),
+            Value::String(context.name.to_owned()),
+        );
+    }
+
+    data
+}
+
+/// Hashes the variables of a context, so incremental syncs notice when they change.
+///
+/// Exports come from the repository so their changes show up in git, and options
+/// are left out as they aren't meant for templates and change between runs. Commit metadata
+/// is left out too, it changes with every commit and would make every context sync in full.
+fn vars_hash(context: &ServerContext, conf: &EnvConf) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (name, value) in template_data(context, conf, &BTreeMap::new()) {
+        if !name.starts_with("SERVER_SYNC_") && !conf.git_vars.contains_key(&name) {
+            name.hash(&mut hasher);
+            value.to_string().hash(&mut hasher);
+        }
+    }
+
+    hasher.finish()
+}
+
+fn check_existing(
+    destination: &Path,
+    rendered: &str,
+    encoding: &OutputEncoding,
+    conf: &EnvConf,
+) -> anyhow::Result<bool> {
+    // New files are diffed against nothing in diff only mode, so the whole file shows up for review.
+    let existing_bytes = match destination.exists() {
+        true => read(destination).context("Read existing file")?,
+        false if conf.get_flag("SERVER_SYNC_DIFF_ONLY") => vec![],
+        false => return Ok(false),
+    };
+
+    let existing_contents = match encoding.decode(&existing_bytes) {
+        None => return Ok(false),
+        Some(value) => value,
+    };
+
+    if destination.exists()
+        && conf.get_flag("SERVER_SYNC_IGNORE_WHITESPACE")
+        && strip_whitespace(&existing_contents) == strip_whitespace(rendered)
+    {
+        debug!("File {} only differs in whitespace", destination.display());
+        return Ok(true);
+    }
+
+    let diff = TextDiff::from_lines(existing_contents.as_str(), rendered);
+    if !conf.get_flag("SERVER_SYNC_QUIET") {
+        conf.diff_format
+            .print(&diff, destination, destination.exists());
+    }
+
+    if diff.ratio() == 1.0 {
+        // Decoding strips any BOM, so compare the raw bytes to catch a changed BOM.
+        return Ok(
+            destination.exists() && existing_bytes == encoding.encode(destination, rendered)?
+        );
+    }
+
+    Ok(false)
+}
+
+/// Strips trailing whitespace from every line and drops blank lines, for comparing contents
+/// without cosmetic differences.
+fn strip_whitespace(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Creates the shared handlebars registry, this is built once and never mutated afterwards.
+fn new_handlerbars<'b>(repo_dir: &Path) -> anyhow::Result<Handlebars<'b>> {
+    debug!("Creating Handlebars instance...");
+
+    let mut handlebars = Handlebars::new();
+
+    handlebars.set_strict_mode(true); // Report missing variables as errors
+    handlebars.register_escape_fn(handlebars::no_escape); // Disable HTML escaping
+    helpers::register_helpers(&mut handlebars);
+    register_partials(&mut handlebars, &repo_dir.join(PARTIALS_DIR))
+        .context("Register partials")?;
+
+    Ok(handlebars)
+}
+
+/// Registers every file in the partials directory as a partial named after the file without its extension.
+fn register_partials(handlebars: &mut Handlebars, partials_dir: &Path) -> anyhow::Result<()> {
+    if !partials_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut partials = read_dir(partials_dir)
+        .context("Read partials directory")?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    partials.sort();
+
+    for path in partials {
+        let name = path
+            .file_stem()
+            .context("Get partial name")?
+            .to_string_lossy()
+            .to_string();
+        let contents = get_contents(&path)?
+            .with_context(|| format!("Partial {} isn't a utf8 file", path.display()))?;
+
+        trace!("Registering partial {}", name);
+        handlebars
+            .register_partial(&name, contents)
+            .with_context(|| format!("Register partial {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Applies the mode and ownership to a path, leaving whatever already matches alone
+/// so unchanged files keep their ctime.
+fn fix_permissions(path: &Path, mode: u32, conf: &EnvConf) -> anyhow::Result<()> {
+    fix_owned_permissions(path, mode, conf.ownership, conf)
+}
+
+/// Sets the mode and owner of a path, with an owner other than the configured one.
+fn fix_owned_permissions(
+    path: &Path,
+    mode: u32,
+    ownership: Option<(u32, u32)>,
+    conf: &EnvConf,
+) -> anyhow::Result<()> {
+    apply_permissions(path, mode, ownership, conf).map_err(|source| {
+        SyncError::PermissionFailure {
+            path: path.to_owned(),
+            source,
+        }
+        .into()
+    })
+}
+
+fn apply_permissions(
+    path: &Path,
+    mode: u32,
+    ownership: Option<(u32, u32)>,
+    conf: &EnvConf,
+) -> anyhow::Result<()> {
+    if path.is_symlink() {
+        return Ok(());
+    }
+
+    let current = conf.permissions.mode(path)?;
+    if current != mode {
+        trace!(
+            "Changing mode of {} from {:o} to {:o}",
+            path.display(),
+            current,
+            mode
+        );
+        conf.permissions.set_mode(path, mode)?;
+    }
+
+    if let Some((uid, gid)) = ownership {
+        let (current_uid, current_gid) = conf.permissions.owner(path)?;
+        if (current_uid, current_gid) != (uid, gid) {
+            trace!(
+                "Changing owner of {} from {}:{} to {}:{}",
+                path.display(),
+                current_uid,
+                current_gid,
+                uid,
+                gid
+            );
+            conf.permissions.set_owner(path, uid, gid)?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,50 @@
+use anyhow::Context;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Reads a `key=value` manifest for a context, preserving the order of entries.
+///
+/// A manifest of the same name in the contexts directory is inherited by every context,
+/// entries in the context's own manifest replace inherited entries with the same key
+/// and any new keys are appended after the inherited ones.
+pub fn read_manifest(source_root: &Path, name: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut entries = match source_root.parent() {
+        Some(contexts_root) => read_entries(contexts_root, name)?,
+        None => vec![],
+    };
+
+    for (key, value) in read_entries(source_root, name)? {
+        match entries.iter_mut().find(|(existing, _)| *existing == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key, value)),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads the entries of a single manifest file, blank lines and lines starting with `#` are ignored.
+fn read_entries(root: &Path, name: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let manifest_path = root.join(name);
+    let mut entries = vec![];
+
+    if !manifest_path.exists() {
+        return Ok(entries);
+    }
+
+    let contents = read_to_string(&manifest_path)
+        .with_context(|| format!("Read manifest {}", manifest_path.display()))?;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Invalid line in {}: {}", name, line))?;
+
+        entries.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(entries)
+}
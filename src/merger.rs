@@ -0,0 +1,308 @@
+use crate::hocon_format;
+use crate::manifest::read_manifest;
+use anyhow::{format_err, Context};
+use globset::{Glob, GlobMatcher};
+use hocon::Hocon;
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use toml::value::Table;
+
+pub const MERGE_MANIFEST: &str = ".server-sync-merge";
+/// A first line in a template that replaces the destination instead of merging into it.
+const OVERWRITE_MARKER: &str = "# server-sync: overwrite";
+
+/// How a rendered file is reconciled with the file already at the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeDriver {
+    /// Replace the destination with the rendered file.
+    TextOverwrite,
+    /// Deep merge the rendered YAML document into the existing one.
+    YamlDeep,
+    /// Deep merge the rendered JSON document into the existing one.
+    JsonDeep,
+    /// Deep merge the rendered TOML document into the existing one.
+    TomlDeep,
+    /// Deep merge the rendered HOCON document into the existing one.
+    HoconDeep,
+    /// Merge `key=value` lines, keeping the existing order and comments.
+    Properties,
+    /// Append the rendered file to the existing one unless it's already there.
+    Append,
+    /// Copy the source byte for byte without templating.
+    Binary,
+}
+
+impl FromStr for MergeDriver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "text-overwrite" => Ok(MergeDriver::TextOverwrite),
+            "yaml-deep" => Ok(MergeDriver::YamlDeep),
+            "json-deep" => Ok(MergeDriver::JsonDeep),
+            "toml-deep" => Ok(MergeDriver::TomlDeep),
+            "hocon-deep" => Ok(MergeDriver::HoconDeep),
+            "properties" => Ok(MergeDriver::Properties),
+            "append" => Ok(MergeDriver::Append),
+            "binary" => Ok(MergeDriver::Binary),
+            other => Err(format_err!(
+                "Unknown merge driver {}, expected text-overwrite, yaml-deep, json-deep, toml-deep, hocon-deep, properties, append or binary",
+                other
+            )),
+        }
+    }
+}
+
+impl MergeDriver {
+    /// The driver used for a file without a matching rule in the merge manifest.
+    pub fn for_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => MergeDriver::JsonDeep,
+            Some("toml") => MergeDriver::TomlDeep,
+            Some("conf" | "hocon") => MergeDriver::HoconDeep,
+            Some("yaml" | "yml") => MergeDriver::YamlDeep,
+            _ => MergeDriver::TextOverwrite,
+        }
+    }
+
+    /// Merges the rendered contents into the existing contents of the destination.
+    ///
+    /// A document that can't be parsed fails with a `DocumentError`, so the file can be skipped.
+    pub fn merge(&self, existing: &str, rendered: &str) -> Result<String, DocumentError> {
+        self.merge_documents(existing, rendered)
+            .map_err(DocumentError::from)
+    }
+
+    fn merge_documents(&self, existing: &str, rendered: &str) -> anyhow::Result<String> {
+        match self {
+            MergeDriver::TextOverwrite | MergeDriver::Binary => Ok(rendered.to_string()),
+            MergeDriver::JsonDeep => {
+                let mut existing =
+                    parse_object(serde_json::from_str(existing)).context("Parse existing JSON")?;
+                let rendered =
+                    parse_object(serde_json::from_str(rendered)).context("Parse rendered JSON")?;
+
+                existing.merge(rendered);
+                Ok(serde_json::to_string_pretty(&existing)? + "\n")
+            }
+            MergeDriver::YamlDeep => {
+                let mut existing =
+                    parse_object(serde_yaml::from_str(existing)).context("Parse existing YAML")?;
+                let rendered =
+                    parse_object(serde_yaml::from_str(rendered)).context("Parse rendered YAML")?;
+
+                existing.merge(rendered);
+                Ok(serde_yaml::to_string(&existing)?)
+            }
+            MergeDriver::TomlDeep => {
+                let mut existing =
+                    toml::from_str::<Table>(existing).context("Parse existing TOML")?;
+                let rendered = toml::from_str::<Table>(rendered).context("Parse rendered TOML")?;
+
+                existing.merge(rendered);
+                // Serializing a Value emits plain values before tables, which a bare Table doesn't.
+                Ok(toml::to_string(&toml::Value::Table(existing))?)
+            }
+            MergeDriver::HoconDeep => {
+                let mut existing = hocon_format::parse(existing).context("Parse existing HOCON")?;
+                let rendered = hocon_format::parse(rendered).context("Parse rendered HOCON")?;
+
+                existing.merge(rendered);
+                Ok(hocon_format::to_string(&existing))
+            }
+            MergeDriver::Properties => Ok(merge_properties(existing, rendered)),
+            MergeDriver::Append => {
+                if existing.contains(rendered) {
+                    return Ok(existing.to_string());
+                }
+
+                let mut appended = existing.to_string();
+                if !appended.is_empty() && !appended.ends_with('\n') {
+                    appended.push('\n');
+                }
+
+                appended.push_str(rendered);
+                Ok(appended)
+            }
+        }
+    }
+}
+
+/// Strips the overwrite marker from the top of a template, if it has one.
+pub fn strip_overwrite_marker(contents: &str) -> Option<&str> {
+    let (first_line, rest) = contents.split_once('\n').unwrap_or((contents, ""));
+    match first_line.trim() == OVERWRITE_MARKER {
+        true => Some(rest),
+        false => None,
+    }
+}
+
+/// A structured document that isn't valid, this only affects its own file.
+#[derive(Debug)]
+pub struct DocumentError(anyhow::Error);
+
+impl Display for DocumentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for DocumentError {}
+
+impl From<anyhow::Error> for DocumentError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(err)
+    }
+}
+
+/// Checks that a rendered file parses in the format of its extension,
+/// files without a known structured extension are always valid.
+pub fn validate(path: &Path, rendered: &str) -> Result<(), DocumentError> {
+    let result = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str::<Value>(rendered)
+            .map(drop)
+            .context("Rendered JSON is invalid"),
+        Some("yaml" | "yml") => serde_yaml::from_str::<serde_yaml::Value>(rendered)
+            .map(drop)
+            .context("Rendered YAML is invalid"),
+        Some("toml") => toml::from_str::<Table>(rendered)
+            .map(drop)
+            .context("Rendered TOML is invalid"),
+        _ => Ok(()),
+    };
+
+    result.map_err(DocumentError::from)
+}
+
+pub trait Mergable {
+    /// Merges other into self, values from other win over existing values.
+    ///
+    /// Arrays are combined without duplicates, keeping the existing elements first in their order.
+    fn merge(&mut self, other: Self);
+}
+
+impl Mergable for Map<String, Value> {
+    fn merge(&mut self, other: Self) {
+        for (key, value) in other {
+            match (self.get_mut(&key), value) {
+                (Some(Value::Object(existing)), Value::Object(value)) => existing.merge(value),
+                (Some(Value::Array(existing)), Value::Array(value)) => {
+                    for element in value {
+                        if !existing.contains(&element) {
+                            existing.push(element);
+                        }
+                    }
+                }
+                (_, value) => {
+                    self.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+impl Mergable for Table {
+    fn merge(&mut self, other: Self) {
+        for (key, value) in other {
+            match (self.get_mut(&key), value) {
+                (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                    existing.merge(value)
+                }
+                (Some(toml::Value::Array(existing)), toml::Value::Array(value)) => {
+                    for element in value {
+                        if !existing.contains(&element) {
+                            existing.push(element);
+                        }
+                    }
+                }
+                (_, value) => {
+                    self.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+impl Mergable for Hocon {
+    fn merge(&mut self, other: Self) {
+        match (self, other) {
+            (Hocon::Hash(existing), Hocon::Hash(other)) => {
+                for (key, value) in other {
+                    match existing.get_mut(&key) {
+                        Some(existing) => existing.merge(value),
+                        None => {
+                            existing.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (Hocon::Array(existing), Hocon::Array(other)) => {
+                for element in other {
+                    if !existing.contains(&element) {
+                        existing.push(element);
+                    }
+                }
+            }
+            (existing, other) => *existing = other,
+        }
+    }
+}
+
+fn parse_object<E>(result: Result<Value, E>) -> anyhow::Result<Map<String, Value>>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match result? {
+        Value::Object(map) => Ok(map),
+        Value::Null => Ok(Map::new()),
+        _ => Err(format_err!("Expected the document to be a map")),
+    }
+}
+
+/// Replaces the value of existing keys in place and appends any new keys at the end.
+fn merge_properties(existing: &str, rendered: &str) -> String {
+    let mut lines = existing.lines().map(str::to_string).collect::<Vec<_>>();
+
+    for line in rendered.lines() {
+        let key = match property_key(line) {
+            None => continue,
+            Some(key) => key,
+        };
+
+        match lines.iter_mut().find(|l| property_key(l) == Some(key)) {
+            Some(existing) => *existing = line.to_string(),
+            None => lines.push(line.to_string()),
+        }
+    }
+
+    let mut merged = lines.join("\n");
+    merged.push('\n');
+    merged
+}
+
+fn property_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+        return None;
+    }
+
+    line.split_once(['=', ':'])
+        .map(|(key, _)| key.trim())
+        .filter(|key| !key.is_empty())
+}
+
+/// Loads the glob to merge driver rules for a context, later rules win over earlier ones.
+pub fn load_manifest(source_root: &Path) -> anyhow::Result<Vec<(GlobMatcher, MergeDriver)>> {
+    read_manifest(source_root, MERGE_MANIFEST)?
+        .into_iter()
+        .map(|(glob, driver)| {
+            let matcher = Glob::new(&glob)
+                .with_context(|| format!("Invalid merge glob {}", glob))?
+                .compile_matcher();
+
+            Ok((matcher, driver.parse()?))
+        })
+        .collect()
+}
@@ -0,0 +1,26 @@
+use anyhow::format_err;
+use std::str::FromStr;
+
+/// How information requested from the command line is printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One value per line for people.
+    Text,
+    /// A JSON document for scripts.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format_err!(
+                "Unknown output format {}, expected text or json",
+                other
+            )),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+
+/// Applies modes and ownership to synced files.
+///
+/// Platforms without unix permissions get an implementation that skips them,
+/// so the rest of the sync doesn't need to care which platform it runs on.
+pub trait PermissionManager: Send + Sync {
+    /// Gets the mode bits of a file.
+    fn mode(&self, path: &Path) -> anyhow::Result<u32>;
+
+    fn set_mode(&self, path: &Path, mode: u32) -> anyhow::Result<()>;
+
+    /// Gets the user and group ids owning a file.
+    fn owner(&self, path: &Path) -> anyhow::Result<(u32, u32)>;
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> anyhow::Result<()>;
+
+    /// Looks up the id of a user by name.
+    fn user_id(&self, name: &str) -> anyhow::Result<u32>;
+
+    /// Looks up the id of a group by name.
+    fn group_id(&self, name: &str) -> anyhow::Result<u32>;
+}
+
+/// Gets the permission manager for the platform this was built for.
+pub fn native() -> Box<dyn PermissionManager> {
+    #[cfg(unix)]
+    return Box::new(unix::UnixPermissions);
+
+    #[cfg(not(unix))]
+    return Box::new(other::NoPermissions);
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::PermissionManager;
+    use anyhow::Context;
+    use file_owner::{Group, Owner};
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    pub struct UnixPermissions;
+
+    impl PermissionManager for UnixPermissions {
+        fn mode(&self, path: &Path) -> anyhow::Result<u32> {
+            let metadata = path.metadata().context("Read file mode")?;
+            Ok(metadata.permissions().mode() & 0o7777)
+        }
+
+        fn set_mode(&self, path: &Path, mode: u32) -> anyhow::Result<()> {
+            set_permissions(path, Permissions::from_mode(mode)).context("Set permissions")
+        }
+
+        fn owner(&self, path: &Path) -> anyhow::Result<(u32, u32)> {
+            let metadata = path.metadata().context("Read file owner")?;
+            Ok((metadata.uid(), metadata.gid()))
+        }
+
+        fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+            file_owner::set_owner_group(path, Owner::from_uid(uid), Group::from_gid(gid))
+                .context("Setting file owner and group")
+        }
+
+        fn user_id(&self, name: &str) -> anyhow::Result<u32> {
+            Ok(Owner::from_name(name)
+                .with_context(|| format!("Look up user {}", name))?
+                .id())
+        }
+
+        fn group_id(&self, name: &str) -> anyhow::Result<u32> {
+            Ok(Group::from_name(name)
+                .with_context(|| format!("Look up group {}", name))?
+                .id())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use super::PermissionManager;
+    use anyhow::format_err;
+    use std::path::Path;
+
+    /// Leaves permissions to the platform, modes and owners don't map onto its access control.
+    pub struct NoPermissions;
+
+    impl PermissionManager for NoPermissions {
+        fn mode(&self, _path: &Path) -> anyhow::Result<u32> {
+            Ok(0o644)
+        }
+
+        fn set_mode(&self, _path: &Path, _mode: u32) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn owner(&self, _path: &Path) -> anyhow::Result<(u32, u32)> {
+            Ok((0, 0))
+        }
+
+        fn set_owner(&self, _path: &Path, _uid: u32, _gid: u32) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn user_id(&self, name: &str) -> anyhow::Result<u32> {
+            Err(format_err!("Can't look up user {} on this platform", name))
+        }
+
+        fn group_id(&self, name: &str) -> anyhow::Result<u32> {
+            Err(format_err!("Can't look up group {} on this platform", name))
+        }
+    }
+}
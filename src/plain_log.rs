@@ -0,0 +1,39 @@
+use crate::json_log::strip_colours;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Forwards records to another logger with the colours the log macros add removed,
+/// for output that isn't going to a terminal.
+pub struct PlainLogger {
+    inner: Box<dyn Log>,
+}
+
+impl PlainLogger {
+    pub fn init(level: LevelFilter, inner: Box<dyn Log>) -> anyhow::Result<()> {
+        log::set_boxed_logger(Box::new(PlainLogger { inner }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for PlainLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let message = strip_colours(&record.args().to_string());
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,31 @@
+use crate::config::EnvConf;
+use crate::json_log::LogFormat;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::LevelFilter;
+use std::io::IsTerminal;
+
+/// Creates the progress bar for the files of a context, drawn on stderr.
+///
+/// It's hidden unless stderr is a terminal and only the info level is logged in the pretty format,
+/// as anything more verbose or a diff would be drawn over by the bar.
+pub fn context_bar(conf: &EnvConf, context: &str, files: usize) -> ProgressBar {
+    let json = conf
+        .get_env("SERVER_SYNC_LOG_FORMAT")
+        .and_then(|format| format.parse::<LogFormat>().ok())
+        == Some(LogFormat::Json);
+    if json
+        || log::max_level() != LevelFilter::Info
+        || conf.get_flag("SERVER_SYNC_DIFF_ONLY")
+        || !std::io::stderr().is_terminal()
+    {
+        return ProgressBar::hidden();
+    }
+
+    let style = ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} {wide_msg}")
+        .expect("Progress template is valid")
+        .progress_chars("=> ");
+
+    ProgressBar::new(files as u64)
+        .with_style(style)
+        .with_prefix(context.to_owned())
+}
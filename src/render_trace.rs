@@ -0,0 +1,101 @@
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement};
+use serde_json::{Map, Value};
+use simplelog::trace;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// How many `-v` flags it takes to log the variables every rendered file uses.
+pub const RENDER_TRACE_VERBOSITY: u8 = 4;
+
+/// Logs each variable a template references with the value it renders with,
+/// values of secret looking variables and ones read from secret files are hidden.
+pub fn trace_bindings(relative_path: &Path, contents: &str, data: &Map<String, Value>) {
+    let template = match Template::compile(contents) {
+        Ok(template) => template,
+        // Rendering reports the error.
+        Err(_) => return,
+    };
+
+    let mut names = BTreeSet::new();
+    collect_template(&template, &mut names);
+
+    trace!(
+        "Rendering {} with {} variables",
+        relative_path.display(),
+        names.len()
+    );
+    for name in names {
+        let secret = crate::is_secret_name(&name)
+            || data.contains_key(&format!("{}_FILE", name.split('.').next().unwrap_or("")));
+        match lookup(data, &name) {
+            Some(_) if secret => trace!("  {} = <redacted>", name),
+            Some(value) => trace!("  {} = {}", name, value),
+            None => trace!("  {} isn't a top level variable", name),
+        }
+    }
+}
+
+/// Follows a dotted path like `database.host` through the variables.
+fn lookup<'a>(data: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    let mut parts = name.trim_start_matches("this.").split('.');
+    let mut value = data.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Value::Object(map) => map.get(part)?,
+            Value::Array(values) => values.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+fn collect_template(template: &Template, names: &mut BTreeSet<String>) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::HtmlExpression(helper)
+            | TemplateElement::Expression(helper)
+            | TemplateElement::HelperBlock(helper) => collect_helper(helper, names),
+            TemplateElement::DecoratorExpression(decorator)
+            | TemplateElement::DecoratorBlock(decorator)
+            | TemplateElement::PartialExpression(decorator)
+            | TemplateElement::PartialBlock(decorator) => {
+                decorator
+                    .params
+                    .iter()
+                    .chain(decorator.hash.values())
+                    .for_each(|param| collect_parameter(param, names));
+                if let Some(template) = &decorator.template {
+                    collect_template(template, names);
+                }
+            }
+            TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+        }
+    }
+}
+
+fn collect_helper(helper: &HelperTemplate, names: &mut BTreeSet<String>) {
+    collect_parameter(&helper.name, names);
+    helper
+        .params
+        .iter()
+        .chain(helper.hash.values())
+        .for_each(|param| collect_parameter(param, names));
+    for template in helper.template.iter().chain(helper.inverse.iter()) {
+        collect_template(template, names);
+    }
+}
+
+fn collect_parameter(param: &Parameter, names: &mut BTreeSet<String>) {
+    match param {
+        Parameter::Path(handlebars::Path::Relative((_, raw))) if raw != "this" => {
+            names.insert(raw.to_owned());
+        }
+        Parameter::Subexpression(subexpression) => {
+            if let TemplateElement::Expression(helper) = subexpression.element.as_ref() {
+                collect_helper(helper, names);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,109 @@
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// What syncing a single file did to its destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileChange {
+    Unchanged,
+    /// The file didn't exist and was written with this many bytes.
+    Created(u64),
+    /// The file was backed up and replaced with this many bytes.
+    Updated(u64),
+    /// The file was replaced with this many bytes without a backup.
+    Overwritten(u64),
+    /// The file wasn't written as its `server-sync-if` condition was false.
+    Skipped,
+    /// The file's condition was false and its existing copy was removed.
+    Removed {
+        backed_up: bool,
+    },
+}
+
+/// Counts what happened to every file across the sync.
+#[derive(Default)]
+pub struct SyncReport {
+    created: usize,
+    updated: usize,
+    backed_up: usize,
+    unchanged: usize,
+    skipped: usize,
+    copied: usize,
+    pruned: usize,
+    bytes_written: u64,
+    /// The destinations that were created, updated or pruned.
+    changed_files: Vec<PathBuf>,
+}
+
+impl SyncReport {
+    /// Records a synced file, copied is set for files copied without templating.
+    pub fn record(&mut self, destination: &Path, change: FileChange, copied: bool) {
+        match change {
+            FileChange::Unchanged => self.unchanged += 1,
+            FileChange::Skipped => self.skipped += 1,
+            FileChange::Removed { backed_up } => return self.record_pruned(destination, backed_up),
+            FileChange::Created(bytes) => {
+                self.created += 1;
+                self.bytes_written += bytes;
+            }
+            FileChange::Updated(bytes) => {
+                self.updated += 1;
+                self.backed_up += 1;
+                self.bytes_written += bytes;
+            }
+            FileChange::Overwritten(bytes) => {
+                self.updated += 1;
+                self.bytes_written += bytes;
+            }
+        }
+
+        if change != FileChange::Unchanged && change != FileChange::Skipped {
+            self.changed_files.push(destination.to_owned());
+        }
+
+        if copied && change != FileChange::Unchanged {
+            self.copied += 1;
+        }
+    }
+
+    /// Records a destination file that was removed because its source is gone or it was skipped.
+    pub fn record_pruned(&mut self, destination: &Path, backed_up: bool) {
+        self.changed_files.push(destination.to_owned());
+        self.pruned += 1;
+        if backed_up {
+            self.backed_up += 1;
+        }
+    }
+
+    /// The counts and changed files as JSON, for reporting the sync to other tools.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "created": self.created,
+            "updated": self.updated,
+            "backed_up": self.backed_up,
+            "unchanged": self.unchanged,
+            "skipped": self.skipped,
+            "copied": self.copied,
+            "pruned": self.pruned,
+            "bytes_written": self.bytes_written,
+            "changed_files": self.changed_files,
+        })
+    }
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} backed up, {} unchanged, {} skipped, {} copied without templating, {} pruned, {} bytes written",
+            self.created,
+            self.updated,
+            self.backed_up,
+            self.unchanged,
+            self.skipped,
+            self.copied,
+            self.pruned,
+            self.bytes_written
+        )
+    }
+}
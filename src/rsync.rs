@@ -0,0 +1,126 @@
+use crate::backup::BackupMode;
+use crate::config::EnvConf;
+use anyhow::{format_err, Context};
+use simplelog::{trace, warn};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// How synced files are written to their destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Every file is written to its destination by the sync itself.
+    Builtin,
+    /// Every file of a context is rendered into a staging directory that's delivered with rsync.
+    Rsync,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "builtin" => Ok(Backend::Builtin),
+            "rsync" => Ok(Backend::Rsync),
+            other => Err(format_err!(
+                "Unknown backend {}, expected builtin or rsync",
+                other
+            )),
+        }
+    }
+}
+
+/// Checks if contexts can be delivered with rsync, warning about why not when the built-in writer is used instead.
+pub fn usable(conf: &EnvConf) -> bool {
+    if conf.backup_mode == BackupMode::Numbered && conf.backs_up_files() {
+        warn!("rsync can't make numbered backups, writing files with the built-in writer");
+        return false;
+    }
+
+    if conf.get_flag("SERVER_SYNC_REMOVE_SKIPPED") {
+        warn!("rsync can't remove skipped files, writing files with the built-in writer");
+        return false;
+    }
+
+    if conf.write_delay.is_some() {
+        warn!("rsync can't be throttled, writing files with the built-in writer");
+        return false;
+    }
+
+    let available = Command::new("rsync")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !available {
+        warn!("rsync isn't available, writing files with the built-in writer");
+    }
+
+    available
+}
+
+/// Copies a staged context over its destination, only files whose contents differ are replaced
+/// and they're backed up first unless backups are disabled.
+///
+/// Returns the paths relative to the destination of the files that were written and whether each was created.
+pub fn deliver(
+    staged: &Path,
+    destination: &Path,
+    conf: &EnvConf,
+) -> anyhow::Result<Vec<(PathBuf, bool)>> {
+    let mut cmd = Command::new("rsync");
+    // Modes and owners are set by the sync afterwards, the same as for files it writes itself,
+    // and times aren't copied so files with the same contents are left alone.
+    cmd.args([
+        "--archive",
+        "--no-perms",
+        "--no-owner",
+        "--no-group",
+        "--no-times",
+        "--checksum",
+        "--out-format=%i %n",
+    ]);
+    if conf.backs_up_files() {
+        cmd.arg("--backup")
+            .arg(format!("--suffix={}", backup_suffix(conf)?));
+    }
+
+    let mut source = staged.as_os_str().to_owned();
+    source.push("/");
+    let mut target = destination.as_os_str().to_owned();
+    target.push("/");
+    cmd.arg(source).arg(target);
+
+    let output = cmd.output().context("Run rsync")?;
+    if !output.status.success() {
+        return Err(format_err!(
+            "rsync to {} failed -> {}",
+            destination.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    trace!("rsync output -> <blue>{}", stdout.trim());
+
+    // Each line is the change summary like `>f+++++++++` followed by the path, directories are left out.
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(changes, _)| matches!(changes.as_bytes().get(1), Some(b'f' | b'L')))
+        .map(|(changes, path)| (PathBuf::from(path), changes[2..].bytes().all(|c| c == b'+')))
+        .collect())
+}
+
+/// Gets what rsync appends to the name of a replaced file, so backups are named like the built-in writer's.
+fn backup_suffix(conf: &EnvConf) -> anyhow::Result<String> {
+    let backup = conf
+        .backup_mode
+        .backup_path(Path::new("file"), &conf.backup_suffix)?;
+
+    Ok(backup
+        .to_string_lossy()
+        .trim_start_matches("file")
+        .to_string())
+}
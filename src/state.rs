@@ -0,0 +1,285 @@
+use anyhow::Context;
+use serde_json::{json, Map, Value};
+use simplelog::debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read_to_string, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const STATE_FILE: &str = ".server-sync-state.json";
+
+/// What the last sync of a context did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContextState {
+    /// The number of files synced.
+    pub files: usize,
+    /// The commit the context was synced at, there's none for a local source.
+    pub commit: Option<String>,
+    /// The hash of the variables the context was rendered with.
+    pub vars: Option<u64>,
+}
+
+/// The record of the last successful sync, read by external tools between runs.
+pub struct SyncState {
+    /// The synced commit, there's none for a local source.
+    pub commit: Option<String>,
+    pub branch: String,
+    /// Every context synced by this or an earlier run.
+    pub contexts: BTreeMap<String, ContextState>,
+    /// The fingerprints of the files written or checked during the sync.
+    pub files: BTreeMap<PathBuf, Fingerprint>,
+    /// The destination paths managed by each context, relative to its destination.
+    pub managed: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+impl SyncState {
+    /// Writes the state as JSON through a temporary file, so readers never see a partial state.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let last_sync = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("Get current time")?
+            .as_secs();
+
+        let contexts = self
+            .contexts
+            .iter()
+            .map(|(name, state)| {
+                (
+                    name.to_owned(),
+                    json!({
+                        "files": state.files,
+                        "commit": state.commit,
+                        "vars": state.vars,
+                    }),
+                )
+            })
+            .collect::<Map<String, Value>>();
+
+        let files = self
+            .files
+            .iter()
+            .map(|(path, fingerprint)| {
+                (
+                    path.to_string_lossy().to_string(),
+                    json!({
+                        "hash": fingerprint.hash,
+                        "len": fingerprint.len,
+                        "modified": fingerprint.modified,
+                    }),
+                )
+            })
+            .collect::<Map<String, Value>>();
+
+        let managed = self
+            .managed
+            .iter()
+            .map(|(name, paths)| {
+                let paths = paths
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>();
+                (name.to_owned(), json!(paths))
+            })
+            .collect::<Map<String, Value>>();
+
+        let state = json!({
+            "last_sync": last_sync,
+            "commit": self.commit,
+            "branch": self.branch,
+            "success": true,
+            "contexts": contexts,
+            "files": files,
+            "managed": managed,
+        });
+
+        let temp_path = path.with_file_name(format!(
+            "{}.tmp",
+            path.file_name()
+                .context("Get state file name")?
+                .to_string_lossy()
+        ));
+
+        // A local source is never cloned, so its directory in the repository storage may not exist yet.
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).context("Create state directory")?;
+        }
+
+        let mut file = File::create(&temp_path).context("Create temporary state file")?;
+        file.write_all(serde_json::to_string_pretty(&state)?.as_bytes())
+            .context("Write state file")?;
+        file.sync_all().context("Flush state file")?;
+        rename(&temp_path, path).context("Move state file into place")?;
+
+        Ok(())
+    }
+}
+
+/// Reads the state of every context synced so far, contexts recorded before each had
+/// their own commit get the commit of the whole sync.
+pub fn read_contexts(state_path: &Path) -> BTreeMap<String, ContextState> {
+    let state = read_to_string(state_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok());
+    let commit = state
+        .as_ref()
+        .and_then(|state| Some(state.get("commit")?.as_str()?.to_string()));
+
+    state
+        .as_ref()
+        .and_then(|state| state.get("contexts")?.as_object())
+        .map(|contexts| {
+            contexts
+                .iter()
+                .map(|(name, context)| {
+                    let state = ContextState {
+                        files: context
+                            .get("files")
+                            .and_then(Value::as_u64)
+                            .unwrap_or_default() as usize,
+                        commit: match context.get("commit") {
+                            Some(commit) => commit.as_str().map(str::to_string),
+                            None => commit.clone(),
+                        },
+                        vars: context.get("vars").and_then(Value::as_u64),
+                    };
+                    (name.to_owned(), state)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads the paths each context managed as of the last successful sync,
+/// a missing or unreadable state manages nothing.
+pub fn read_managed(state_path: &Path) -> BTreeMap<String, BTreeSet<PathBuf>> {
+    read_to_string(state_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .and_then(|state| state.get("managed")?.as_object().cloned())
+        .map(|managed| {
+            managed
+                .iter()
+                .map(|(name, paths)| {
+                    let paths = paths
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|path| path.as_str().map(PathBuf::from))
+                        .collect();
+                    (name.to_owned(), paths)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The hash of what was rendered for a file and the metadata of the destination once it was synced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash: u64,
+    pub len: u64,
+    pub modified: u64,
+}
+
+impl Fingerprint {
+    /// Fingerprints the destination as it is on disk, if it exists.
+    pub fn of(destination: &Path, hash: u64) -> Option<Self> {
+        let metadata = destination.metadata().ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos() as u64;
+
+        Some(Self {
+            hash,
+            len: metadata.len(),
+            modified,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            hash: value.get("hash")?.as_u64()?,
+            len: value.get("len")?.as_u64()?,
+            modified: value.get("modified")?.as_u64()?,
+        })
+    }
+}
+
+/// The fingerprints from the last sync, used to skip files that haven't changed since then.
+#[derive(Default)]
+pub struct FileCache {
+    previous: BTreeMap<PathBuf, Fingerprint>,
+    current: Mutex<BTreeMap<PathBuf, Fingerprint>>,
+}
+
+impl FileCache {
+    /// Loads the fingerprints from the state file, a missing or unreadable state starts an empty cache.
+    pub fn load(state_path: &Path) -> Self {
+        let previous = read_to_string(state_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+            .and_then(|state| state.get("files")?.as_object().cloned())
+            .map(|files| {
+                files
+                    .iter()
+                    .filter_map(|(path, value)| {
+                        Some((PathBuf::from(path), Fingerprint::from_json(value)?))
+                    })
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        debug!("Loaded {} cached fingerprints", previous.len());
+
+        Self {
+            previous,
+            current: Mutex::default(),
+        }
+    }
+
+    /// Checks if the same content was synced to the destination last time and it hasn't been touched since.
+    pub fn is_fresh(&self, destination: &Path, hash: u64) -> bool {
+        match (
+            self.previous.get(destination),
+            Fingerprint::of(destination, hash),
+        ) {
+            (Some(previous), Some(current)) if *previous == current => {
+                self.current
+                    .lock()
+                    .unwrap()
+                    .insert(destination.to_owned(), current);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the destination as synced with the given hash.
+    pub fn record(&self, destination: &Path, hash: u64) {
+        if let Some(fingerprint) = Fingerprint::of(destination, hash) {
+            self.current
+                .lock()
+                .unwrap()
+                .insert(destination.to_owned(), fingerprint);
+        }
+    }
+
+    /// Carries the fingerprint from the last sync over for a destination that wasn't checked this time.
+    pub fn keep(&self, destination: &Path) {
+        if let Some(previous) = self.previous.get(destination) {
+            self.current
+                .lock()
+                .unwrap()
+                .insert(destination.to_owned(), *previous);
+        }
+    }
+
+    pub fn into_files(self) -> BTreeMap<PathBuf, Fingerprint> {
+        self.current.into_inner().unwrap()
+    }
+}
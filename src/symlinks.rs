@@ -0,0 +1,111 @@
+use crate::report::FileChange;
+use anyhow::{format_err, Context};
+use simplelog::debug;
+use std::fs::{canonicalize, read_link, remove_file};
+use std::path::{Component, Path, PathBuf};
+
+/// Recreates a symlink from the source tree at the destination, pointing at the same relative target.
+///
+/// Only relative targets that stay inside the destination root are allowed,
+/// so a committed link can't be used to reach outside of what's being synced.
+pub fn sync_symlink(
+    source: &Path,
+    dest: &Path,
+    destination_root: &Path,
+    diff_only: bool,
+) -> anyhow::Result<FileChange> {
+    let target = read_link(source).context("Read symlink")?;
+    check_target(&target, dest, destination_root)?;
+
+    let existed = dest.symlink_metadata().is_ok();
+    if existed && read_link(dest).ok().as_ref() == Some(&target) {
+        debug!("Symlink {} is up to date", dest.display());
+        return Ok(FileChange::Unchanged);
+    }
+
+    if diff_only {
+        debug!("Not linking {} in diff only mode", dest.display());
+    } else {
+        if existed {
+            remove_file(dest).context("Remove existing destination")?;
+        }
+        link(&target, dest)?;
+    }
+
+    Ok(match existed {
+        true => FileChange::Overwritten(0),
+        false => FileChange::Created(0),
+    })
+}
+
+/// Gets the path a synced file is written to, which is its destination unless that's a symlink.
+///
+/// A symlink is refused unless following is enabled, then the file it points at is merged,
+/// backed up and replaced instead so the link itself stays in place.
+pub fn write_target(dest: &Path, follow: bool) -> anyhow::Result<PathBuf> {
+    if !dest.is_symlink() {
+        return Ok(dest.to_path_buf());
+    }
+
+    if !follow {
+        return Err(format_err!(
+            "Destination {} is a symlink, set SERVER_SYNC_FOLLOW_DEST_SYMLINKS to write to the file it points at",
+            dest.display()
+        ));
+    }
+
+    let target = match canonicalize(dest) {
+        Ok(target) => target,
+        // A dangling link points at the file about to be created.
+        Err(_) => dest
+            .parent()
+            .context("Get destination folder")?
+            .join(read_link(dest).context("Read destination symlink")?),
+    };
+    debug!(
+        "Destination {} links to {}",
+        dest.display(),
+        target.display()
+    );
+
+    Ok(target)
+}
+
+/// Creates a symlink at `path` pointing at `target`.
+#[cfg(unix)]
+pub fn link(target: &Path, path: &Path) -> anyhow::Result<()> {
+    std::os::unix::fs::symlink(target, path).context("Create symlink")
+}
+
+#[cfg(not(unix))]
+pub fn link(_target: &Path, path: &Path) -> anyhow::Result<()> {
+    Err(format_err!(
+        "Can't create symlink {} on this platform, set SERVER_SYNC_FOLLOW_SYMLINKS to copy it instead",
+        path.display()
+    ))
+}
+
+/// Fails unless the target resolves to somewhere inside the destination root.
+fn check_target(target: &Path, dest: &Path, destination_root: &Path) -> anyhow::Result<()> {
+    let parent = dest
+        .parent()
+        .and_then(|parent| parent.strip_prefix(destination_root).ok())
+        .context("Get relative symlink folder")?;
+
+    let mut resolved = PathBuf::new();
+    for component in parent.join(target).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir if resolved.pop() => {}
+            _ => {
+                return Err(format_err!(
+                    "Symlink target {} is outside of the destination",
+                    target.display()
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
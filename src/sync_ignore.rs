@@ -0,0 +1,195 @@
+use anyhow::Context;
+use globset::{GlobBuilder, GlobMatcher};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub const IGNORE_FILE: &str = ".syncignore";
+pub const RAW_COPY_FILE: &str = ".rawcopy";
+pub const GIT_ATTRIBUTES_FILE: &str = ".gitattributes";
+const EXPORT_IGNORE: &str = "export-ignore";
+
+struct IgnoreRule {
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// Gitignore style rules from `.syncignore` files, the last matching rule decides if a path is ignored.
+///
+/// The same rules are read from `.rawcopy` files to pick the files that are copied without templating.
+#[derive(Default)]
+pub struct SyncIgnore {
+    rules: Vec<IgnoreRule>,
+    /// Where the source root is relative to the directory the rules are written against.
+    base: PathBuf,
+}
+
+impl SyncIgnore {
+    /// Loads the rules for a context.
+    ///
+    /// A `.syncignore` in the contexts directory applies to every context,
+    /// rules in the context's own file are added after it so they take precedence.
+    pub fn load(source_root: &Path) -> anyhow::Result<Self> {
+        Self::load_file(source_root, IGNORE_FILE)
+    }
+
+    /// Loads the rules for a context from files with the given name, in the same way as `.syncignore`.
+    pub fn load_file(source_root: &Path, file_name: &str) -> anyhow::Result<Self> {
+        let mut ignore = SyncIgnore::default();
+        let ignore_files = source_root
+            .parent()
+            .map(|contexts_root| contexts_root.join(file_name))
+            .into_iter()
+            .chain([source_root.join(file_name)]);
+
+        for ignore_file in ignore_files.filter(|path| path.exists()) {
+            let contents = read_to_string(&ignore_file)
+                .with_context(|| format!("Read {}", ignore_file.display()))?;
+
+            for line in contents.lines() {
+                ignore
+                    .add_rule(line, "")
+                    .with_context(|| format!("Parse {}", ignore_file.display()))?;
+            }
+        }
+
+        Ok(ignore)
+    }
+
+    /// Loads the paths marked `export-ignore` in `.gitattributes` files, like `git archive` leaves them out.
+    ///
+    /// The files in the directories above the context up to the repository root are read first,
+    /// then the ones inside the context, so the deepest file decides like it does for git.
+    pub fn load_export_ignore(repo_root: &Path, source_root: &Path) -> anyhow::Result<Self> {
+        let base = source_root
+            .strip_prefix(repo_root)
+            .context("Get context path in repository")?;
+        let mut ignore = SyncIgnore {
+            rules: vec![],
+            base: base.to_path_buf(),
+        };
+
+        let mut attribute_files = base
+            .ancestors()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|dir| repo_root.join(dir).join(GIT_ATTRIBUTES_FILE))
+            .collect::<Vec<_>>();
+        attribute_files.extend(
+            WalkDir::new(source_root)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|e| e.depth() == 0 || e.file_name() != ".git")
+                .filter_map(Result::ok)
+                .filter(|e| e.file_name() == GIT_ATTRIBUTES_FILE)
+                .map(|e| e.into_path()),
+        );
+
+        for attribute_file in attribute_files.iter().filter(|path| path.is_file()) {
+            let contents = read_to_string(attribute_file)
+                .with_context(|| format!("Read {}", attribute_file.display()))?;
+            let dir = attribute_file
+                .parent()
+                .and_then(|dir| dir.strip_prefix(repo_root).ok())
+                .unwrap_or(Path::new(""))
+                .to_string_lossy()
+                .into_owned();
+
+            for line in contents.lines() {
+                let mut fields = line.split_whitespace();
+                let pattern = match fields.next() {
+                    // Macro definitions and quoted patterns can't mark files directly.
+                    Some(pattern) if !pattern.starts_with(['#', '[', '"']) => pattern,
+                    _ => continue,
+                };
+
+                // The last mention of the attribute on a line wins, unsetting it lets a deeper file re-include paths.
+                let ignored = fields.rev().find_map(|attribute| match attribute {
+                    EXPORT_IGNORE => Some(true),
+                    _ if attribute.starts_with("export-ignore=") => Some(true),
+                    _ if attribute.strip_prefix(['-', '!']) == Some(EXPORT_IGNORE) => Some(false),
+                    _ => None,
+                });
+                let rule = match ignored {
+                    Some(true) => pattern.to_string(),
+                    Some(false) => format!("!{}", pattern),
+                    None => continue,
+                };
+
+                ignore
+                    .add_rule(&rule, &dir)
+                    .with_context(|| format!("Parse {}", attribute_file.display()))?;
+            }
+        }
+
+        Ok(ignore)
+    }
+
+    /// Adds a gitignore style rule, written relative to `dir` within the directory the rules are matched against.
+    fn add_rule(&mut self, line: &str, dir: &str) -> anyhow::Result<()> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        // Patterns without a slash match at any depth, the rest are relative to the context root.
+        let pattern = match pattern.contains('/') {
+            true => pattern.trim_start_matches('/').to_string(),
+            false => format!("**/{}", pattern),
+        };
+        let pattern = match dir.is_empty() {
+            true => pattern,
+            false => format!("{}/{}", dir.trim_end_matches('/'), pattern),
+        };
+
+        let matcher = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid ignore pattern {}", line))?
+            .compile_matcher();
+
+        self.rules.push(IgnoreRule {
+            matcher,
+            negated,
+            dir_only,
+        });
+
+        Ok(())
+    }
+
+    /// Checks if a path relative to the source root is ignored.
+    ///
+    /// Only the path itself is checked, so directories should be tested before walking into them.
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let path = self.base.join(relative_path);
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.matcher.is_match(&path))
+            .map(|rule| !rule.negated)
+            .unwrap_or(false)
+    }
+
+    /// Checks if a file or any of the directories it's in matches.
+    pub fn matches_within(&self, relative_path: &Path) -> bool {
+        relative_path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.is_ignored(ancestor, true))
+            || self.is_ignored(relative_path, false)
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{format_err, Context};
+use globset::GlobBuilder;
+use serde_json::{Map, Value};
+use simplelog::{debug, warn};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Reads a YAML, TOML or JSON file of structured template variables, picked by the file extension.
+pub fn read_vars_file(path: &Path) -> anyhow::Result<Map<String, Value>> {
+    let contents = read_to_string(path).context("Read vars file")?;
+
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => serde_json::to_value(
+            toml::from_str::<toml::Value>(&contents).context("Parse TOML vars")?,
+        )?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents).context("Parse YAML vars")?,
+        Some("json") => serde_json::from_str(&contents).context("Parse JSON vars")?,
+        _ => {
+            return Err(format_err!(
+                "Unknown vars file type, expected a .toml, .yaml, .yml or .json file"
+            ))
+        }
+    };
+
+    match value {
+        Value::Object(map) => Ok(map),
+        Value::Null => Ok(Map::new()),
+        _ => Err(format_err!("Expected the vars file to be a map")),
+    }
+}
+
+/// Resolves a comma separated list of vars files against the repository,
+/// an entry with a glob pattern expands to every file it matches in path order.
+pub fn vars_files(repo_dir: &Path, raw: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for entry in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        if !entry.contains(['*', '?', '[', '{']) {
+            files.push(repo_dir.join(entry));
+            continue;
+        }
+
+        let matcher = GlobBuilder::new(entry.trim_start_matches("./"))
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid vars file pattern {}", entry))?
+            .compile_matcher();
+        let matched = WalkDir::new(repo_dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".git")
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.into_path())
+            .filter(|path| matcher.is_match(path.strip_prefix(repo_dir).unwrap()))
+            .collect::<Vec<_>>();
+
+        if matched.is_empty() {
+            warn!("Vars file pattern {} didn't match any files", entry);
+        }
+        files.extend(matched);
+    }
+
+    Ok(files)
+}
+
+/// Deep merges the variables of a later vars file over the ones read before it,
+/// nested maps are merged key by key and any other value is replaced.
+pub fn merge_vars(vars: &mut Map<String, Value>, other: Map<String, Value>, source: &Path) {
+    merge_map(vars, other, source, "");
+}
+
+fn merge_map(
+    vars: &mut Map<String, Value>,
+    other: Map<String, Value>,
+    source: &Path,
+    prefix: &str,
+) {
+    for (key, value) in other {
+        let name = format!("{}{}", prefix, key);
+        match (vars.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(value)) => {
+                merge_map(existing, value, source, &format!("{}.", name))
+            }
+            (Some(existing), value) => {
+                if *existing != value {
+                    debug!("{} overrides the variable {}", source.display(), name);
+                }
+                *existing = value;
+            }
+            (None, value) => {
+                vars.insert(key, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::report::SyncReport;
+use anyhow::Context;
+use serde_json::json;
+use simplelog::{debug, warn};
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts a summary of the sync to the webhook, a webhook that can't be reached only logs a warning.
+pub fn post_summary(
+    url: &str,
+    report: &SyncReport,
+    duration: Duration,
+    error: Option<&anyhow::Error>,
+) {
+    let mut payload = report.to_json();
+    payload["status"] = json!(match error {
+        None => "success",
+        Some(_) => "failure",
+    });
+    payload["duration_secs"] = json!(duration.as_secs_f64());
+    if let Some(err) = error {
+        payload["error"] = json!(format!("{:#}", err));
+    }
+
+    debug!("Posting sync summary to webhook");
+    if let Err(err) = post(url, &payload) {
+        warn!("Failed to post sync summary to webhook -> {:#}", err);
+    }
+}
+
+fn post(url: &str, payload: &serde_json::Value) -> anyhow::Result<()> {
+    reqwest::blocking::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .context("Create HTTP client")?
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_string())
+        .send()
+        .context("Send webhook request")?
+        .error_for_status()
+        .context("Webhook rejected the summary")?;
+
+    Ok(())
+}
@@ -0,0 +1,2381 @@
+use std::fs::{create_dir_all, metadata, read, read_dir, read_to_string, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+
+/// A temporary source repository and destination for running the binary against.
+struct Fixture {
+    root: PathBuf,
+    source: PathBuf,
+    storage: PathBuf,
+    destination: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("server-sync-{}-{}", name, std::process::id()));
+        if root.exists() {
+            remove_dir_all(&root).unwrap();
+        }
+
+        let fixture = Self {
+            source: root.join("source"),
+            storage: root.join("storage"),
+            destination: root.join("destination"),
+            root,
+        };
+
+        create_dir_all(&fixture.source).unwrap();
+        create_dir_all(&fixture.destination).unwrap();
+        fixture
+    }
+
+    /// Writes a file relative to the source repository.
+    fn source_file(&self, path: &str, contents: &str) -> &Self {
+        write_file(&self.source.join(path), contents);
+        self
+    }
+
+    /// Writes a file relative to the destination, as if it was already on the server.
+    fn existing_file(&self, path: &str, contents: &str) -> &Self {
+        write_file(&self.destination.join(path), contents);
+        self
+    }
+
+    fn destination_file(&self, path: &str) -> String {
+        read_to_string(self.destination.join(path)).unwrap()
+    }
+
+    fn commit(&self) {
+        git(&self.source, &["init", "-q"]);
+        git(&self.source, &["checkout", "-q", "-b", "master"]);
+        git(&self.source, &["add", "-A"]);
+        git(&self.source, &["commit", "-q", "-m", "Initial commit"]);
+    }
+
+    /// The directory the source repository was cloned into under the repository storage.
+    fn repo_storage(&self) -> PathBuf {
+        read_dir(&self.storage)
+            .expect("Read repository storage")
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_dir())
+            .expect("Find the cloned repository")
+    }
+
+    fn sync(&self, contexts: &[&str]) -> ExitStatus {
+        self.sync_with(contexts, &[])
+    }
+
+    /// Runs the binary with extra environment variables.
+    fn sync_with(&self, contexts: &[&str], env: &[(&str, &str)]) -> ExitStatus {
+        self.command(contexts, env)
+            .status()
+            .expect("Run server_sync")
+    }
+
+    /// Runs the binary and captures its output.
+    fn output(&self, contexts: &[&str]) -> Output {
+        self.command(contexts, &[])
+            .output()
+            .expect("Run server_sync")
+    }
+
+    fn command(&self, contexts: &[&str], env: &[(&str, &str)]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_server_sync"));
+        for context in contexts {
+            cmd.args(["-c", context]);
+        }
+
+        cmd.env("SERVER_SYNC_ENV", self.root.join(".server_env"))
+            .env("SERVER_SYNC_REPO", &self.source)
+            .env("SERVER_SYNC_BRANCH", "master")
+            .env("SERVER_SYNC_DESTINATION", &self.destination)
+            .env("SERVER_SYNC_REPO_STORAGE", &self.storage)
+            .env("UID", current_uid())
+            .env("motd", "Welcome")
+            .envs(env.iter().copied());
+        cmd
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
+
+fn write_file(path: &Path, contents: &str) {
+    create_dir_all(path.parent().unwrap()).unwrap();
+    write(path, contents).unwrap();
+}
+
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "-c",
+            "user.name=Server Sync",
+            "-c",
+            "user.email=server-sync@localhost",
+        ])
+        .args(args)
+        .status()
+        .expect("Run git");
+
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+fn current_uid() -> String {
+    let output = Command::new("id").arg("-u").output().expect("Run id");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+#[test]
+fn syncs_context_to_destination() {
+    let fixture = Fixture::new("smoke");
+    fixture.source_file(
+        "contexts/survival/config/server.yml",
+        "name: {{server_name}}\nmotd: {{motd}}\n",
+    );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(
+        fixture.destination_file("config/server.yml"),
+        "name: survival\nmotd: Welcome\n"
+    );
+    assert!(fixture
+        .repo_storage()
+        .join(".server-sync-state.json")
+        .exists());
+}
+
+#[test]
+fn missing_ssh_key_fails_before_cloning() {
+    let fixture = Fixture::new("missing-ssh-key");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let status = fixture.sync_with(
+        &["survival"],
+        &[("SERVER_SYNC_SSH_KEY", "/nonexistent/deploy_key")],
+    );
+
+    assert_eq!(status.code(), Some(19));
+    assert!(!fixture.storage.exists());
+}
+
+#[test]
+fn pins_the_synced_commit() {
+    let fixture = Fixture::new("pinned-ref");
+    fixture.source_file("contexts/survival/motd.txt", "First\n");
+    fixture.commit();
+    let first = Command::new("git")
+        .arg("-C")
+        .arg(&fixture.source)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .unwrap();
+    let first = String::from_utf8(first.stdout).unwrap().trim().to_string();
+
+    fixture.source_file("contexts/survival/motd.txt", "Second\n");
+    git(&fixture.source, &["commit", "-q", "-am", "Second commit"]);
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Second\n");
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_REF", &first)])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "First\n");
+
+    assert!(!fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_REF", "0123456789abcdef")])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "First\n");
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Second\n");
+}
+
+#[test]
+fn merges_nested_json_objects() {
+    let fixture = Fixture::new("json-objects");
+    fixture
+        .source_file(
+            "contexts/survival/plugin.json",
+            r#"{"database": {"host": "db", "pool": {"max": 10}}, "motd": "{{motd}}"}"#,
+        )
+        .existing_file(
+            "plugin.json",
+            r#"{"database": {"host": "localhost", "pool": {"min": 1}}, "debug": true}"#,
+        );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let merged: serde_json::Value =
+        serde_json::from_str(&fixture.destination_file("plugin.json")).unwrap();
+    assert_eq!(
+        merged,
+        serde_json::json!({
+            "database": {"host": "db", "pool": {"min": 1, "max": 10}},
+            "debug": true,
+            "motd": "Welcome"
+        })
+    );
+    assert!(fixture.destination.join("plugin.json.bak").exists());
+}
+
+#[test]
+fn merges_json_arrays_without_duplicates() {
+    let fixture = Fixture::new("json-arrays");
+    fixture
+        .source_file(
+            "contexts/survival/plugin.json",
+            r#"{"worlds": ["world", "nether", "end"]}"#,
+        )
+        .existing_file("plugin.json", r#"{"worlds": ["world", "creative"]}"#);
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let merged: serde_json::Value =
+        serde_json::from_str(&fixture.destination_file("plugin.json")).unwrap();
+    assert_eq!(
+        merged,
+        serde_json::json!({"worlds": ["world", "creative", "nether", "end"]})
+    );
+}
+
+#[test]
+fn stages_destinations_under_the_staging_root() {
+    let fixture = Fixture::new("staging-root");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/plugin.json", r#"{"worlds": ["nether"]}"#)
+        .existing_file("plugin.json", r#"{"worlds": ["world"]}"#);
+    fixture.commit();
+    let staging = fixture.root.join("staging");
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[("SERVER_SYNC_STAGING_ROOT", staging.to_str().unwrap())]
+        )
+        .success());
+
+    let staged = staging.join(fixture.destination.strip_prefix("/").unwrap());
+    assert_eq!(
+        read_to_string(staged.join("motd.txt")).unwrap(),
+        "Welcome\n"
+    );
+    let merged: serde_json::Value =
+        serde_json::from_str(&read_to_string(staged.join("plugin.json")).unwrap()).unwrap();
+    assert_eq!(merged, serde_json::json!({"worlds": ["world", "nether"]}));
+    assert!(!fixture.destination.join("motd.txt").exists());
+    assert_eq!(
+        fixture.destination_file("plugin.json"),
+        r#"{"worlds": ["world"]}"#
+    );
+}
+
+#[test]
+fn merges_toml_arrays_of_tables_in_order() {
+    let fixture = Fixture::new("toml-arrays");
+    fixture
+        .source_file(
+            "contexts/survival/plugin.toml",
+            "[essentials]\n[[essentials.kits]]\nname = \"starter\"\nitems = [\"sword\"]\n\n\
+             [[essentials.kits]]\nname = \"vip\"\nitems = [\"bow\"]\n",
+        )
+        .existing_file(
+            "plugin.toml",
+            "[essentials]\nenabled = true\nworlds = [\"world\"]\n\n\
+             [[essentials.kits]]\nname = \"starter\"\nitems = [\"sword\"]\n",
+        );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let merged: toml::Value = toml::from_str(&fixture.destination_file("plugin.toml")).unwrap();
+    let expected: toml::Value = toml::from_str(
+        "[essentials]\nenabled = true\nworlds = [\"world\"]\n\n\
+         [[essentials.kits]]\nname = \"starter\"\nitems = [\"sword\"]\n\n\
+         [[essentials.kits]]\nname = \"vip\"\nitems = [\"bow\"]\n",
+    )
+    .unwrap();
+    assert_eq!(merged, expected);
+}
+
+#[test]
+fn merges_hocon_documents() {
+    let fixture = Fixture::new("hocon");
+    fixture
+        .source_file(
+            "contexts/survival/plugin.conf",
+            "server.motd = \"{{motd}}\"\nworlds = [nether]\n",
+        )
+        .existing_file(
+            "plugin.conf",
+            "# Written by the plugin\nserver { port = 25565, motd = old }\nworlds = [world]\n",
+        );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    let merged = fixture.destination_file("plugin.conf");
+    assert_eq!(
+        merged,
+        "server {\n  port = 25565\n  motd = \"Welcome\"\n}\nworlds = [\"world\", \"nether\"]\n"
+    );
+
+    // Syncing the merged output again has to leave it as it is.
+    fixture.existing_file("plugin.conf", &merged);
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("plugin.conf"), merged);
+}
+
+#[test]
+fn overwrites_files_that_are_not_merged() {
+    let fixture = Fixture::new("merge-extensions");
+    fixture
+        .source_file("contexts/survival/a.json", r#"{"b": 2}"#)
+        .source_file("contexts/survival/a.yml", "b: 2\n")
+        .source_file(
+            "contexts/survival/c.yml",
+            "# server-sync: overwrite\nd: 2\n",
+        )
+        .existing_file("a.json", r#"{"a": 1}"#)
+        .existing_file("a.yml", "a: 1\n")
+        .existing_file("c.yml", "c: 1\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_MERGE_EXTENSIONS", "yml")])
+        .success());
+
+    assert_eq!(fixture.destination_file("a.json"), r#"{"b": 2}"#);
+    assert_eq!(fixture.destination_file("a.yml"), "a: 1\nb: 2\n");
+    assert_eq!(fixture.destination_file("c.yml"), "d: 2\n");
+}
+
+#[test]
+fn merges_with_a_separate_merge_source() {
+    let fixture = Fixture::new("merge-source");
+    let live = fixture.root.join("live");
+    fixture
+        .source_file("contexts/survival/plugin.json", r#"{"motd": "{{motd}}"}"#)
+        .existing_file("plugin.json", r#"{"stale": true}"#);
+    write_file(&live.join("plugin.json"), r#"{"debug": true}"#);
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[("SERVER_SYNC_MERGE_SOURCE_survival", live.to_str().unwrap())]
+        )
+        .success());
+
+    let merged: serde_json::Value =
+        serde_json::from_str(&fixture.destination_file("plugin.json")).unwrap();
+    assert_eq!(
+        merged,
+        serde_json::json!({"debug": true, "motd": "Welcome"})
+    );
+    assert_eq!(
+        read_to_string(live.join("plugin.json")).unwrap(),
+        r#"{"debug": true}"#
+    );
+}
+
+#[test]
+fn writes_files_with_their_output_encoding() {
+    let fixture = Fixture::new("output-encoding");
+    fixture
+        .source_file("contexts/survival/plain.txt", "é\n")
+        .source_file("contexts/survival/bom.txt", "é\n")
+        .source_file("contexts/survival/le.txt", "é\n")
+        .source_file("contexts/survival/be.txt", "é\n")
+        .source_file(
+            "contexts/survival/.server-sync-encoding",
+            "bom.txt=utf-8+bom\nle.txt=utf-16le+bom\nbe.txt=utf-16be+bom\n",
+        );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let bytes = |path: &str| read(fixture.destination.join(path)).unwrap();
+    assert_eq!(bytes("plain.txt"), b"\xC3\xA9\n");
+    assert_eq!(bytes("bom.txt"), b"\xEF\xBB\xBF\xC3\xA9\n");
+    assert_eq!(bytes("le.txt"), b"\xFF\xFE\xE9\x00\n\x00");
+    assert_eq!(bytes("be.txt"), b"\xFE\xFF\x00\xE9\x00\n");
+}
+
+#[test]
+fn fails_on_characters_the_encoding_cannot_represent() {
+    let fixture = Fixture::new("unmappable-encoding");
+    fixture
+        .source_file("contexts/survival/legacy.ini", "name=名前\n")
+        .source_file(
+            "contexts/survival/.server-sync-encoding",
+            "legacy.ini=windows-1252\n",
+        );
+    fixture.commit();
+
+    // Keeping going reports every error with its causes.
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_KEEP_GOING", "true")])
+        .output()
+        .expect("Run server_sync");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("legacy.ini has characters that windows-1252 can't represent"));
+    assert!(!fixture.destination.join("legacy.ini").exists());
+}
+
+#[test]
+fn numbered_backups_keep_every_version() {
+    let fixture = Fixture::new("numbered-backups");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "First\n")
+        .existing_file("motd.txt.1.bak", "Older\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_BACKUP_MODE", "numbered")])
+        .success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("motd.txt.1.bak"), "Older\n");
+    assert_eq!(fixture.destination_file("motd.txt.2.bak"), "First\n");
+}
+
+#[test]
+fn max_backups_removes_the_oldest_backups() {
+    let fixture = Fixture::new("max-backups");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Third\n")
+        .existing_file("motd.txt.1.bak", "First\n")
+        .existing_file("motd.txt.2.bak", "Second\n")
+        .existing_file("motd.txt.notes.bak", "Notes\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_BACKUP_MODE", "numbered"),
+                ("SERVER_SYNC_MAX_BACKUPS", "2"),
+            ],
+        )
+        .success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert!(!fixture.destination.join("motd.txt.1.bak").exists());
+    assert_eq!(fixture.destination_file("motd.txt.2.bak"), "Second\n");
+    assert_eq!(fixture.destination_file("motd.txt.3.bak"), "Third\n");
+    assert_eq!(fixture.destination_file("motd.txt.notes.bak"), "Notes\n");
+}
+
+#[test]
+fn quiet_only_prints_the_summary_and_errors() {
+    let fixture = Fixture::new("quiet");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_DIFF_ONLY", "true")])
+        .arg("-q")
+        .output()
+        .expect("Run server_sync");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.starts_with("Summary of changes that weren't written: 0 created, 1 updated"));
+
+    let output = fixture
+        .command(&["survival"], &[])
+        .args(["-q", "-v"])
+        .output()
+        .expect("Run server_sync");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(fixture.destination_file("motd.txt"), "Old\n");
+}
+
+#[test]
+fn renders_git_metadata() {
+    let fixture = Fixture::new("git-metadata");
+    fixture.source_file(
+        "contexts/survival/deployed.txt",
+        "{{git_commit}} {{git_commit_short}} {{git_branch}} {{git_author}}\n",
+    );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let head = Command::new("git")
+        .arg("-C")
+        .arg(&fixture.source)
+        .args(["log", "-1", "--format=%H %h"])
+        .output()
+        .expect("Run git");
+    assert_eq!(
+        fixture.destination_file("deployed.txt"),
+        format!(
+            "{} master Server Sync\n",
+            String::from_utf8_lossy(&head.stdout).trim()
+        )
+    );
+}
+
+#[test]
+fn expands_context_patterns() {
+    let fixture = Fixture::new("context-patterns");
+    fixture
+        .source_file("contexts/mc-survival-1/one.txt", "one\n")
+        .source_file("contexts/mc-survival-2/two.txt", "two\n")
+        .source_file("contexts/mc-creative-1/creative.txt", "creative\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["mc-survival-*"]).success());
+
+    assert_eq!(fixture.destination_file("one.txt"), "one\n");
+    assert_eq!(fixture.destination_file("two.txt"), "two\n");
+    assert!(!fixture.destination.join("creative.txt").exists());
+}
+
+#[test]
+fn reports_every_template_that_fails_to_render() {
+    let fixture = Fixture::new("render-errors");
+    fixture
+        .source_file("contexts/survival/a.txt", "{{missing_a}}\n")
+        .source_file("contexts/survival/b.txt", "{{motd}}\n")
+        .source_file("contexts/survival/c.txt", "{{missing_c}}\n");
+    fixture.commit();
+
+    let output = fixture.output(&["survival"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert_eq!(fixture.destination_file("b.txt"), "Welcome\n");
+    assert!(stderr.contains("Sync finished with 2 errors"));
+    assert!(stderr.contains("missing_a") && stderr.contains("missing_c"));
+}
+
+#[test]
+fn skips_files_that_fail_to_merge() {
+    let fixture = Fixture::new("merge-errors");
+    fixture
+        .source_file("contexts/survival/plugin.toml", "enabled = true\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("plugin.toml", "enabled = = true\n");
+    fixture.commit();
+
+    let output = fixture.output(&["survival"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(
+        fixture.destination_file("plugin.toml"),
+        "enabled = = true\n"
+    );
+    assert!(stderr.contains("plugin.toml") && stderr.contains("Parse existing TOML"));
+}
+
+#[test]
+fn reads_contexts_from_the_configured_directory() {
+    let fixture = Fixture::new("contexts-dir");
+    fixture.source_file("servers/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture.output(&["survival"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+        "The contexts directory {} doesn't exist",
+        fixture.repo_storage().join("contexts").display()
+    )));
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_CONTEXTS_DIR", "servers")])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn filters_files_with_include_and_exclude_globs() {
+    let fixture = Fixture::new("include-exclude");
+    fixture
+        .source_file("contexts/survival/server.properties", "motd={{motd}}\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/cache/data.txt", "data\n");
+    fixture.commit();
+
+    let status = fixture
+        .command(&["survival"], &[])
+        .args(["--include", "*.properties"])
+        .status()
+        .expect("Run server_sync");
+    assert!(status.success());
+    assert_eq!(
+        fixture.destination_file("server.properties"),
+        "motd=Welcome\n"
+    );
+    assert!(!fixture.destination.join("motd.txt").exists());
+
+    assert!(fixture.sync(&["survival"]).success());
+    std::fs::remove_file(fixture.source.join("contexts/survival/motd.txt")).unwrap();
+    write(
+        fixture.source.join("contexts/survival/cache/data.txt"),
+        "new\n",
+    )
+    .unwrap();
+    git(&fixture.source, &["commit", "-qam", "Remove motd"]);
+
+    // Excluded files are still managed, so pruning leaves them alone.
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_EXCLUDE", "cache/**"),
+                ("SERVER_SYNC_PRUNE", "true")
+            ]
+        )
+        .success());
+    assert!(!fixture.destination.join("motd.txt").exists());
+    assert_eq!(fixture.destination_file("cache/data.txt"), "data\n");
+}
+
+#[test]
+fn lists_contexts_without_syncing() {
+    let fixture = Fixture::new("list-contexts");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/creative/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&[], &[("SERVER_SYNC_OUTPUT", "json")])
+        .arg("--list-contexts")
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("[\"creative\",\"survival\"]"));
+    assert!(!fixture.destination.join("motd.txt").exists());
+}
+
+#[test]
+fn reads_secrets_from_files() {
+    let fixture = Fixture::new("secret-files");
+    fixture.source_file("contexts/survival/db.txt", "{{db_password}}\n");
+    fixture.commit();
+    let secrets = fixture.root.join("secrets");
+    write_file(&secrets.join("db_password"), "hunter2\n");
+    write_file(
+        &secrets.join("destination"),
+        fixture.destination.join("nested").to_str().unwrap(),
+    );
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[
+                ("db_password", ""),
+                (
+                    "db_password_FILE",
+                    secrets.join("db_password").to_str().unwrap(),
+                ),
+            ],
+        )
+        .env_remove("SERVER_SYNC_DESTINATION")
+        .env("SERVER_SYNC_DESTINATION_FILE", secrets.join("destination"))
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    assert_eq!(fixture.destination_file("nested/db.txt"), "hunter2\n");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("secret file"));
+}
+
+#[test]
+fn path_settings_ending_in_file_are_not_secrets() {
+    let fixture = Fixture::new("path-settings");
+    fixture
+        .source_file("vars.yml", "server:\n  port: 25565\n")
+        .source_file("contexts/survival/server.txt", "{{server.port}}\n");
+    fixture.commit();
+    let state = fixture.root.join("state/sync.json");
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_VARS_FILE", "vars.yml"),
+                ("SERVER_SYNC_STATE_FILE", state.to_str().unwrap()),
+                ("LOG_FILE", "/nonexistent/server.log"),
+            ],
+        )
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    assert_eq!(fixture.destination_file("server.txt"), "25565\n");
+    assert!(state.exists());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("secret file"));
+}
+
+#[test]
+fn prints_config_with_sources() {
+    let fixture = Fixture::new("print-config");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[(
+                "SERVER_SYNC_WEBHOOK_URL",
+                "https://example.com/hooks/secret",
+            )],
+        )
+        .args(["--print-config", "--branch", "release"])
+        .output()
+        .expect("Run server_sync");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success());
+    assert!(stdout.contains("SERVER_SYNC_BRANCH=release (command args)"));
+    assert!(stdout.contains(&format!(
+        "SERVER_SYNC_DESTINATION={} (process env)",
+        fixture.destination.display()
+    )));
+    assert!(stdout.contains("SERVER_SYNC_WEBHOOK_URL=<redacted> (process env)"));
+    assert!(!stdout.contains("secret"));
+    assert!(!fixture.destination.join("motd.txt").exists());
+}
+
+#[test]
+fn traces_the_variables_each_template_renders_with() {
+    let fixture = Fixture::new("render-trace");
+    fixture.source_file(
+        "contexts/survival/motd.txt",
+        "{{motd}} {{#if api_token}}{{upper api_token}}{{/if}}\n",
+    );
+    fixture.commit();
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[("motd", "Welcome"), ("api_token", "hunter2")],
+        )
+        .arg("-vvvv")
+        .output()
+        .expect("Run server_sync");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success());
+    assert!(stdout.contains("Rendering motd.txt with 2 variables"));
+    assert!(stdout.contains("motd = \"Welcome\""));
+    assert!(stdout.contains("api_token = <redacted>"));
+    assert!(!stdout
+        .lines()
+        .any(|line| line.contains("api_token =") && line.contains("hunter2")));
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome HUNTER2\n");
+}
+
+#[test]
+fn validate_reports_every_problem_without_syncing() {
+    let fixture = Fixture::new("validate-config");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/broken.txt", "{{#if motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival", "creative"], &[])
+        .arg("validate")
+        .output()
+        .expect("Run server_sync");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("Found 2 problems"));
+    assert!(stderr.contains("Context creative doesn't exist"));
+    assert!(stderr.contains("broken.txt"));
+    assert!(!fixture.destination.join("motd.txt").exists());
+
+    write(
+        fixture.source.join("contexts/survival/broken.txt"),
+        "{{motd}}\n",
+    )
+    .unwrap();
+    git(&fixture.source, &["commit", "-qam", "Fix template"]);
+
+    let status = fixture
+        .command(&["survival"], &[])
+        .arg("validate")
+        .status()
+        .expect("Run server_sync");
+    assert!(status.success());
+    assert!(!fixture.destination.join("motd.txt").exists());
+}
+
+#[test]
+fn validates_rendered_documents() {
+    let fixture = Fixture::new("validate");
+    fixture
+        .source_file("contexts/survival/config.yml", "motd: {{motd}}: now\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture.output(&["survival"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("Rendered YAML is invalid"));
+    assert!(!fixture.destination.join("config.yml").exists());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_VALIDATE", "false")])
+        .success());
+    assert_eq!(
+        fixture.destination_file("config.yml"),
+        "motd: Welcome: now\n"
+    );
+}
+
+#[test]
+fn renders_same_named_files_independently() {
+    let fixture = Fixture::new("same-names");
+    fixture
+        .source_file("contexts/survival/lobby/config.yml", "world: lobby\n")
+        .source_file("contexts/survival/arena/config.yml", "world: arena\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    assert_eq!(
+        fixture.destination_file("lobby/config.yml"),
+        "world: lobby\n"
+    );
+    assert_eq!(
+        fixture.destination_file("arena/config.yml"),
+        "world: arena\n"
+    );
+}
+
+#[test]
+fn leaves_up_to_date_files_alone() {
+    let fixture = Fixture::new("up-to-date");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Welcome\n");
+    fixture.commit();
+    let modified = metadata(fixture.destination.join("motd.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    let output = fixture.output(&["survival"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 unchanged"));
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert!(!fixture.destination.join("motd.txt.bak").exists());
+    assert_eq!(
+        metadata(fixture.destination.join("motd.txt"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        modified
+    );
+}
+
+#[test]
+fn resyncs_files_changed_since_the_last_sync() {
+    let fixture = Fixture::new("file-cache");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    let state = read_to_string(fixture.repo_storage().join(".server-sync-state.json")).unwrap();
+    assert!(state.contains("motd.txt"));
+
+    fixture.existing_file("motd.txt", "Edited by hand\n");
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn caches_binary_files_by_source_hash() {
+    let fixture = Fixture::new("binary-cache");
+    let region = [0xffu8, 0xfe, 0x00, 0x01];
+    create_dir_all(fixture.source.join("contexts/survival")).unwrap();
+    write(fixture.source.join("contexts/survival/r.0.0.mca"), region).unwrap();
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    let state = read_to_string(fixture.repo_storage().join(".server-sync-state.json")).unwrap();
+    assert!(state.contains("r.0.0.mca"));
+
+    write(fixture.destination.join("r.0.0.mca"), [0u8; 4]).unwrap();
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(read(fixture.destination.join("r.0.0.mca")).unwrap(), region);
+}
+
+#[test]
+fn rsync_backend_falls_back_to_the_builtin_writer() {
+    let fixture = Fixture::new("rsync-fallback");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_BACKEND", "rsync"),
+                ("SERVER_SYNC_BACKUP_MODE", "numbered"),
+            ],
+        )
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("rsync can't make numbered backups"));
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("motd.txt.1.bak"), "Old\n");
+}
+
+#[test]
+fn verifies_written_files() {
+    let fixture = Fixture::new("verify-writes");
+    let region = [0xffu8, 0xfe, 0x00, 0x01];
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    write(fixture.source.join("contexts/survival/r.0.0.mca"), region).unwrap();
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_VERIFY_WRITES", "true")])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(read(fixture.destination.join("r.0.0.mca")).unwrap(), region);
+}
+
+#[test]
+fn prunes_files_removed_from_the_source() {
+    let fixture = Fixture::new("prune");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/old.txt", "old\n")
+        .existing_file("world.dat", "server data\n");
+    fixture.commit();
+    assert!(fixture.sync(&["survival"]).success());
+
+    git(&fixture.source, &["rm", "-q", "contexts/survival/old.txt"]);
+    git(&fixture.source, &["commit", "-q", "-m", "Remove old"]);
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("old.txt"), "old\n");
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_PRUNE", "true")])
+        .success());
+
+    assert!(!fixture.destination.join("old.txt").exists());
+    assert_eq!(fixture.destination_file("old.txt.bak"), "old\n");
+    assert_eq!(fixture.destination_file("world.dat"), "server data\n");
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn btrfs_snapshots_refuse_other_filesystems() {
+    let fixture = Fixture::new("btrfs-snapshot");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    let status = fixture.sync_with(
+        &["survival"],
+        &[("SERVER_SYNC_BACKUP_MODE", "btrfs-snapshot")],
+    );
+
+    assert_eq!(status.code(), Some(19));
+    assert_eq!(fixture.destination_file("motd.txt"), "Old\n");
+}
+
+#[test]
+fn force_overwrites_without_backups() {
+    let fixture = Fixture::new("force");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_FORCE", "true")])
+        .success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert!(!fixture.destination.join("motd.txt.bak").exists());
+}
+
+#[test]
+fn copies_raw_files_without_templating() {
+    let fixture = Fixture::new("raw-copy");
+    fixture
+        .source_file("contexts/survival/.rawcopy", "*.mustache\n")
+        .source_file("contexts/survival/page.mustache", "{{name}}\n")
+        .source_file("contexts/survival/web/.noformat", "")
+        .source_file("contexts/survival/web/data.json", "{\"a\": \"{{b}}\"}\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("page.mustache"), "{{name}}\n");
+    assert_eq!(
+        fixture.destination_file("web/data.json"),
+        "{\"a\": \"{{b}}\"}\n"
+    );
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert!(!fixture.destination.join("web/.noformat").exists());
+    assert!(!fixture.destination.join(".rawcopy").exists());
+}
+
+#[test]
+fn skips_files_marked_export_ignore() {
+    let fixture = Fixture::new("export-ignore");
+    fixture
+        .source_file(
+            ".gitattributes",
+            "contexts/survival/dev/** export-ignore\n*.test export-ignore\n",
+        )
+        .source_file(
+            "contexts/survival/.gitattributes",
+            "keep.test -export-ignore\nnotes.md export-ignore\n",
+        )
+        .source_file("contexts/survival/dev/seed.sql", "insert\n")
+        .source_file("contexts/survival/plugins/a.test", "test\n")
+        .source_file("contexts/survival/keep.test", "kept\n")
+        .source_file("contexts/survival/notes.md", "todo\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("keep.test"), "kept\n");
+    assert!(!fixture.destination.join("dev/seed.sql").exists());
+    assert!(!fixture.destination.join("plugins/a.test").exists());
+    assert!(!fixture.destination.join("notes.md").exists());
+}
+
+#[test]
+fn renders_templates_with_custom_delimiters() {
+    let fixture = Fixture::new("delimiters");
+    fixture
+        .source_file(
+            "contexts/survival/playbook.j2",
+            "{{ item }} = <% motd %>\\<% kept %>\n",
+        )
+        .source_file(
+            "contexts/survival/config.json",
+            "{\"motd\": \"[[motd]]\"}\n",
+        )
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_DELIMITERS", "*.j2=<% %>"),
+                ("SERVER_SYNC_DELIMITERS_survival", "*.json=[[ ]]"),
+            ]
+        )
+        .success());
+    assert_eq!(
+        fixture.destination_file("playbook.j2"),
+        "{{ item }} = Welcome<% kept %>\n"
+    );
+    assert_eq!(
+        fixture.destination_file("config.json"),
+        "{\"motd\": \"Welcome\"}\n"
+    );
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn strict_utf8_fails_on_templates_that_arent_utf8() {
+    let fixture = Fixture::new("strict-utf8");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    write(
+        fixture.source.join("contexts/survival/server.properties"),
+        b"\xff\xfem\x00o\x00t\x00d\x00",
+    )
+    .unwrap();
+    write(
+        fixture.source.join("contexts/survival/icon.png"),
+        b"\x89PNG\xff",
+    )
+    .unwrap();
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(
+        read(fixture.destination.join("server.properties")).unwrap(),
+        b"\xff\xfem\x00o\x00t\x00d\x00"
+    );
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_STRICT_UTF8", "true")])
+        .output()
+        .expect("Run server_sync");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Template server.properties isn't utf8")
+    );
+}
+
+#[test]
+fn normalizes_line_endings() {
+    let fixture = Fixture::new("normalize-eol");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\r\nline\r\nlast")
+        .existing_file("motd.txt", "Welcome\nline\nlast\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_NORMALIZE_EOL", "lf"),
+                ("SERVER_SYNC_ENSURE_TRAILING_NEWLINE", "true"),
+            ],
+        )
+        .success());
+
+    assert_eq!(
+        fixture.destination_file("motd.txt"),
+        "Welcome\nline\nlast\n"
+    );
+    assert!(!fixture.destination.join("motd.txt.bak").exists());
+}
+
+#[test]
+fn ignores_whitespace_only_changes() {
+    let fixture = Fixture::new("ignore-whitespace");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\nline\n")
+        .existing_file("motd.txt", "Welcome  \n\nline\n\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_IGNORE_WHITESPACE", "true")])
+        .success());
+
+    assert_eq!(
+        fixture.destination_file("motd.txt"),
+        "Welcome  \n\nline\n\n"
+    );
+    assert!(!fixture.destination.join("motd.txt.bak").exists());
+}
+
+#[test]
+fn diff_only_leaves_the_destination_untouched() {
+    let fixture = Fixture::new("diff-only");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/new/file.txt", "new\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_DIFF_ONLY", "true")])
+        .success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Old\n");
+    assert!(!fixture.destination.join("new").exists());
+    assert!(!fixture.destination.join("motd.txt.bak").exists());
+}
+
+#[test]
+fn diffs_are_plain_when_not_a_terminal() {
+    let fixture = Fixture::new("plain-diff");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_DIFF_ONLY", "true")])
+        .output()
+        .expect("Run server_sync");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success());
+    assert!(stdout.contains("- Old\n+ Welcome"));
+    assert!(!stdout.contains('\x1b') && !stdout.contains("<red>"));
+}
+
+#[cfg(unix)]
+#[test]
+fn recreates_symlinks_inside_the_destination() {
+    use std::os::unix::fs::symlink;
+
+    let fixture = Fixture::new("symlinks");
+    fixture.source_file("contexts/survival/v2.conf", "v2\n");
+    symlink("v2.conf", fixture.source.join("contexts/survival/latest")).unwrap();
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    let link = fixture.destination.join("latest");
+    assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("v2.conf"));
+
+    std::fs::remove_file(&link).unwrap();
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_FOLLOW_SYMLINKS", "true")])
+        .success());
+    assert!(!link.is_symlink());
+    assert_eq!(fixture.destination_file("latest"), "v2\n");
+
+    let escaping = Fixture::new("symlink-escape");
+    escaping.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    symlink(
+        "../../etc/passwd",
+        escaping.source.join("contexts/survival/escape"),
+    )
+    .unwrap();
+    escaping.commit();
+
+    assert!(!escaping.sync(&["survival"]).success());
+    assert!(escaping
+        .destination
+        .join("escape")
+        .symlink_metadata()
+        .is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn refuses_or_follows_symlinks_at_the_destination() {
+    use std::os::unix::fs::symlink;
+
+    let fixture = Fixture::new("destination-symlink");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+    let real = fixture.root.join("real");
+    write_file(&real.join("motd.txt"), "Old\n");
+    create_dir_all(&fixture.destination).unwrap();
+    let link = fixture.destination.join("motd.txt");
+    symlink(real.join("motd.txt"), &link).unwrap();
+
+    assert!(!fixture.sync(&["survival"]).success());
+    assert!(link.is_symlink());
+    assert_eq!(read_to_string(real.join("motd.txt")).unwrap(), "Old\n");
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[("SERVER_SYNC_FOLLOW_DEST_SYMLINKS", "true")]
+        )
+        .success());
+    assert!(link.is_symlink());
+    assert_eq!(read_to_string(real.join("motd.txt")).unwrap(), "Welcome\n");
+    assert_eq!(read_to_string(real.join("motd.txt.bak")).unwrap(), "Old\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn leaves_matching_permissions_untouched() {
+    use std::os::unix::fs::MetadataExt;
+
+    let fixture = Fixture::new("matching-permissions");
+    fixture.source_file("contexts/survival/config/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    let ctime = |path: &str| {
+        let metadata = std::fs::metadata(fixture.destination.join(path)).unwrap();
+        (metadata.ctime(), metadata.ctime_nsec())
+    };
+    let before = (ctime("config"), ctime("config/motd.txt"));
+
+    // Without the cached fingerprints every file is compared and has its permissions fixed.
+    std::fs::remove_file(fixture.repo_storage().join(".server-sync-state.json")).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!((ctime("config"), ctime("config/motd.txt")), before);
+}
+
+#[cfg(unix)]
+#[test]
+fn applies_front_matter_directives() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("front-matter");
+    fixture
+        .source_file(
+            "contexts/survival/server.json",
+            "---\nmode: 600\nmerge: text-overwrite\ndestination: \"config/{{server_name}}.json\"\n---\n{\"motd\": \"{{motd}}\"}\n",
+        )
+        .source_file(
+            "contexts/survival/creative.txt",
+            "---\nskip_if: creative\n---\ngamemode=creative\n",
+        )
+        .source_file("contexts/survival/docs.yml", "---\nname: docs\n")
+        .existing_file("config/survival.json", "{\"port\": 25565}\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("creative", "true")])
+        .success());
+
+    assert_eq!(
+        fixture.destination_file("config/survival.json"),
+        "{\"motd\": \"Welcome\"}\n"
+    );
+    let mode = metadata(fixture.destination.join("config/survival.json"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(!fixture.destination.join("server.json").exists());
+    assert!(!fixture.destination.join("creative.txt").exists());
+    assert_eq!(fixture.destination_file("docs.yml"), "---\nname: docs\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn preserves_source_modes_with_overrides() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("preserve-mode");
+    fixture
+        .source_file("contexts/survival/start.sh", "#!/bin/sh\n")
+        .source_file("contexts/survival/secret.txt", "hunter2\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    std::fs::set_permissions(
+        fixture.source.join("contexts/survival/start.sh"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_PRESERVE_MODE", "true"),
+                ("SERVER_SYNC_MODES", "secret.*=600"),
+            ],
+        )
+        .success());
+
+    let mode = |path: &str| {
+        std::fs::metadata(fixture.destination.join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(mode("start.sh"), 0o755);
+    assert_eq!(mode("secret.txt"), 0o600);
+    assert_eq!(mode("motd.txt"), 0o644);
+}
+
+#[test]
+fn runs_post_sync_hook_with_changed_files() {
+    let fixture = Fixture::new("post-sync-hook");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/server.properties", "pvp=true\n")
+        .existing_file("server.properties", "pvp=true\n");
+    fixture.commit();
+
+    let hook = ("SERVER_SYNC_HOOK_survival", "cat > ../hook.log");
+    assert!(fixture.sync_with(&["survival"], &[hook]).success());
+    assert_eq!(
+        read_to_string(fixture.root.join("hook.log")).unwrap(),
+        "motd.txt"
+    );
+
+    std::fs::remove_file(fixture.root.join("hook.log")).unwrap();
+    assert!(fixture.sync_with(&["survival"], &[hook]).success());
+    assert!(!fixture.root.join("hook.log").exists());
+}
+
+#[test]
+fn fails_before_syncing_when_destination_isnt_writable() {
+    let fixture = Fixture::new("unwritable-destination");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("not-a-dir", "");
+    fixture.commit();
+
+    let destination = fixture.destination.join("not-a-dir/servers");
+    let output = fixture
+        .command(&["survival"], &[])
+        .env("SERVER_SYNC_DESTINATION", &destination)
+        .output()
+        .expect("Run server_sync");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains(&format!(
+        "Destination {} isn't writable",
+        destination.display()
+    )));
+}
+
+#[test]
+fn retries_git_only_for_network_errors() {
+    let fixture = Fixture::new("git-retries");
+    let retries = ("SERVER_SYNC_GIT_RETRIES", "1");
+
+    let output = fixture
+        .command(&["survival"], &[retries])
+        .env("SERVER_SYNC_REPO", "http://127.0.0.1:1/missing.git")
+        .output()
+        .expect("Run server_sync");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Retrying in 1s (1/1)"));
+
+    let output = fixture
+        .command(&["survival"], &[retries])
+        .env("SERVER_SYNC_REPO", fixture.root.join("missing"))
+        .output()
+        .expect("Run server_sync");
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Retrying"));
+}
+
+#[test]
+fn exits_with_a_code_for_each_kind_of_failure() {
+    let fixture = Fixture::new("exit-codes");
+    fixture.source_file("contexts/survival/broken.txt", "{{#if motd}}\n");
+    fixture.commit();
+
+    let status = fixture
+        .command(&["survival"], &[("SERVER_SYNC_FAIL_FAST", "true")])
+        .status()
+        .expect("Run server_sync");
+    assert_eq!(status.code(), Some(21));
+
+    let status = fixture
+        .command(&["survival"], &[("SERVER_SYNC_GIT_RETRIES", "0")])
+        .env("SERVER_SYNC_REPO", fixture.root.join("missing"))
+        .env(
+            "SERVER_SYNC_REPO_STORAGE",
+            fixture.root.join("missing-storage"),
+        )
+        .status()
+        .expect("Run server_sync");
+    assert_eq!(status.code(), Some(20));
+}
+
+#[test]
+fn skips_files_whose_condition_is_false() {
+    let fixture = Fixture::new("conditional-files");
+    fixture
+        .source_file(
+            "contexts/survival/creative.properties",
+            "{{#server-sync-if creative}}\ngamemode=creative\n{{/server-sync-if}}\n",
+        )
+        .source_file(
+            "contexts/survival/motd.txt",
+            "{{#server-sync-if motd}}\n{{motd}}\n{{/server-sync-if}}\n",
+        )
+        .existing_file("creative.properties", "gamemode=survival\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(
+        fixture.destination_file("creative.properties"),
+        "gamemode=survival\n"
+    );
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_REMOVE_SKIPPED", "true")])
+        .success());
+    assert!(!fixture.destination.join("creative.properties").exists());
+    assert_eq!(
+        fixture.destination_file("creative.properties.bak"),
+        "gamemode=survival\n"
+    );
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("creative", "true")])
+        .success());
+    assert_eq!(
+        fixture.destination_file("creative.properties"),
+        "gamemode=creative\n"
+    );
+}
+
+#[test]
+fn layers_included_contexts_underneath() {
+    let fixture = Fixture::new("includes");
+    fixture
+        .source_file("contexts/base/motd.txt", "Base\n")
+        .source_file("contexts/base/rules.txt", "Be nice, {{server_name}}\n")
+        .source_file("contexts/base/.syncinclude", "common\n")
+        .source_file("contexts/common/ops.txt", "Common ops\n")
+        .source_file("contexts/common/motd.txt", "Common\n")
+        .source_file("contexts/survival/.syncinclude", "# Shared files\nbase\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("rules.txt"), "Be nice, survival\n");
+    assert_eq!(fixture.destination_file("ops.txt"), "Common ops\n");
+    assert!(!fixture.destination.join(".syncinclude").exists());
+}
+
+#[test]
+fn include_cycles_fail_the_sync() {
+    let fixture = Fixture::new("include-cycle");
+    fixture
+        .source_file("contexts/base/.syncinclude", "survival\n")
+        .source_file("contexts/survival/.syncinclude", "base\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture.output(&["survival"]);
+    assert_eq!(output.status.code(), Some(19));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Load manifests for context survival"));
+    assert!(!fixture.destination.join("motd.txt").exists());
+}
+
+#[test]
+fn repositories_share_the_repository_storage() {
+    let fixture = Fixture::new("shared-storage");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let other = fixture.root.join("other");
+    write_file(
+        &other.join("contexts/creative/rules.txt"),
+        "Build anything\n",
+    );
+    git(&other, &["init", "-q"]);
+    git(&other, &["checkout", "-q", "-b", "master"]);
+    git(&other, &["add", "-A"]);
+    git(&other, &["commit", "-q", "-m", "Initial commit"]);
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert!(fixture
+        .command(&["creative"], &[])
+        .env("SERVER_SYNC_REPO", &other)
+        .status()
+        .expect("Run server_sync")
+        .success());
+    // The first repository is updated in its own clone rather than pulling over the other one.
+    assert!(fixture.sync(&["survival"]).success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("rules.txt"), "Build anything\n");
+    let clones = read_dir(&fixture.storage)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join(".git").is_dir())
+        .count();
+    assert_eq!(clones, 2);
+}
+
+#[test]
+fn syncs_contexts_in_submodules() {
+    let fixture = Fixture::new("submodules");
+    let shared = fixture.root.join("shared");
+    write_file(&shared.join("defaults.txt"), "{{motd}}\n");
+    git(&shared, &["init", "-q"]);
+    git(&shared, &["add", "-A"]);
+    git(&shared, &["commit", "-qm", "Shared defaults"]);
+
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+    let allow_file = ["-c", "protocol.file.allow=always"];
+    git(
+        &fixture.source,
+        &[
+            &allow_file[..],
+            &["submodule", "add", "-q", shared.to_str().unwrap()],
+            &["contexts/survival/shared"],
+        ]
+        .concat(),
+    );
+    git(&fixture.source, &["commit", "-qm", "Add shared defaults"]);
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_SUBMODULES", "true"),
+                ("GIT_CONFIG_COUNT", "1"),
+                ("GIT_CONFIG_KEY_0", "protocol.file.allow"),
+                ("GIT_CONFIG_VALUE_0", "always"),
+            ]
+        )
+        .success());
+    assert_eq!(fixture.destination_file("shared/defaults.txt"), "Welcome\n");
+    assert!(!fixture.destination.join("shared/.git").exists());
+}
+
+#[test]
+fn waits_for_or_refuses_a_concurrent_sync() {
+    let fixture = Fixture::new("lock");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let lock = std::fs::File::create(fixture.root.join("storage.lock")).unwrap();
+    lock.lock().unwrap();
+    let status = fixture.sync(&["survival"]);
+    assert_eq!(status.code(), Some(25));
+    assert!(!fixture.destination.join("motd.txt").exists());
+
+    let waiting = fixture
+        .command(&["survival"], &[("SERVER_SYNC_WAIT", "true")])
+        .spawn()
+        .expect("Run server_sync");
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    drop(lock);
+    assert!(waiting.wait_with_output().unwrap().status.success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn syncs_a_local_directory_without_git() {
+    let fixture = Fixture::new("local-source");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[("SERVER_SYNC_REPO", &fixture.source.to_string_lossy())]
+        )
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert!(!fixture.source.join(".git").exists());
+
+    let state = read_to_string(fixture.repo_storage().join(".server-sync-state.json")).unwrap();
+    assert!(state.contains("\"commit\": null"));
+
+    fixture.source_file("contexts/survival/motd.txt", "Goodbye\n");
+    let url = format!("file://{}", fixture.source.display());
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_REPO", &url)])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Goodbye\n");
+}
+
+#[test]
+fn incremental_sync_only_processes_changed_files() {
+    let fixture = Fixture::new("incremental");
+    fixture
+        .source_file("contexts/survival/a.txt", "a {{motd}}\n")
+        .source_file("contexts/survival/b.txt", "b {{motd}}\n");
+    fixture.commit();
+    let incremental = ("SERVER_SYNC_INCREMENTAL", "true");
+
+    assert!(fixture.sync_with(&["survival"], &[incremental]).success());
+    assert_eq!(fixture.destination_file("b.txt"), "b Welcome\n");
+
+    fixture.source_file("contexts/survival/a.txt", "a changed\n");
+    git(&fixture.source, &["commit", "-qam", "Change a"]);
+    std::fs::remove_file(fixture.destination.join("b.txt")).unwrap();
+
+    assert!(fixture.sync_with(&["survival"], &[incremental]).success());
+    assert_eq!(fixture.destination_file("a.txt"), "a changed\n");
+    assert!(!fixture.destination.join("b.txt").exists());
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("b.txt"), "b Welcome\n");
+}
+
+#[test]
+fn incremental_sync_notices_changed_variables() {
+    let fixture = Fixture::new("incremental-vars");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+    let incremental = ("SERVER_SYNC_INCREMENTAL", "true");
+
+    assert!(fixture.sync_with(&["survival"], &[incremental]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+
+    assert!(fixture
+        .sync_with(&["survival"], &[incremental, ("motd", "Goodbye")])
+        .success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Goodbye\n");
+}
+
+#[test]
+fn skips_contexts_without_changes() {
+    let fixture = Fixture::new("only-changed-contexts");
+    fixture
+        .source_file("contexts/survival/survival.txt", "{{motd}}\n")
+        .source_file("contexts/creative/creative.txt", "{{motd}}\n");
+    fixture.commit();
+    let only_changed = ("SERVER_SYNC_ONLY_CHANGED_CONTEXTS", "true");
+
+    assert!(fixture
+        .sync_with(&["survival", "creative"], &[only_changed])
+        .success());
+    assert_eq!(fixture.destination_file("creative.txt"), "Welcome\n");
+
+    fixture.source_file("contexts/survival/survival.txt", "changed\n");
+    git(&fixture.source, &["commit", "-qam", "Change survival"]);
+    std::fs::remove_file(fixture.destination.join("creative.txt")).unwrap();
+
+    assert!(fixture
+        .sync_with(&["survival", "creative"], &[only_changed])
+        .success());
+    assert_eq!(fixture.destination_file("survival.txt"), "changed\n");
+    assert!(!fixture.destination.join("creative.txt").exists());
+
+    assert!(fixture.sync(&["survival", "creative"]).success());
+    assert_eq!(fixture.destination_file("creative.txt"), "Welcome\n");
+}
+
+#[test]
+fn renders_destination_paths() {
+    let fixture = Fixture::new("templated-paths");
+    fixture.source_file(
+        "contexts/survival/{{server_name}}/{{motd}}.txt",
+        "{{motd}}\n",
+    );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(
+        fixture.destination_file("survival/Welcome.txt"),
+        "Welcome\n"
+    );
+
+    assert!(!fixture
+        .sync_with(&["survival"], &[("motd", "../../escaped")])
+        .success());
+    assert!(!fixture.root.join("escaped.txt").exists());
+}
+
+#[test]
+fn fails_when_post_sync_hook_fails() {
+    let fixture = Fixture::new("failing-hook");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(!fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_HOOK_survival", "exit 3")])
+        .success());
+}
+
+#[test]
+fn keeps_going_past_a_failing_post_sync_hook() {
+    let fixture = Fixture::new("keep-going-hook");
+    fixture
+        .source_file("contexts/creative/creative.txt", "{{motd}}\n")
+        .source_file("contexts/survival/survival.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(
+            &["creative", "survival"],
+            &[
+                ("SERVER_SYNC_KEEP_GOING", "true"),
+                ("SERVER_SYNC_HOOK_creative", "exit 3"),
+                ("SERVER_SYNC_HOOK_survival", "exit 3"),
+            ],
+        )
+        .output()
+        .expect("Run server_sync");
+
+    assert!(!output.status.success());
+    assert_eq!(fixture.destination_file("creative.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("survival.txt"), "Welcome\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Sync finished with 2 errors"));
+    assert!(stderr.contains("Run post sync hook for context creative"));
+    assert!(stderr.contains("Run post sync hook for context survival"));
+}
+
+#[test]
+fn pre_sync_hook_sees_staged_files_and_can_abort() {
+    let fixture = Fixture::new("pre-sync-hook");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let failing = (
+        "SERVER_SYNC_PRE_HOOK",
+        "grep -q Goodbye \"$1/survival/motd.txt\"",
+    );
+    assert!(!fixture.sync_with(&["survival"], &[failing]).success());
+    assert!(!fixture.destination.join("motd.txt").exists());
+
+    let passing = (
+        "SERVER_SYNC_PRE_HOOK",
+        "grep -q Welcome \"$1/survival/motd.txt\"",
+    );
+    assert!(fixture.sync_with(&["survival"], &[passing]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn interpolates_env_file_values() {
+    let fixture = Fixture::new("interpolate");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{greeting}}\n")
+        .existing_file("motd.txt", "Old\n");
+    fixture.commit();
+    write(
+        fixture.root.join(".server_env"),
+        "greeting=${motd}, it costs $$5\nSERVER_SYNC_BACKUP_SUFFIX=$suffix.orig\n",
+    )
+    .unwrap();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("suffix", "old")])
+        .success());
+
+    assert_eq!(
+        fixture.destination_file("motd.txt"),
+        "Welcome, it costs $5\n"
+    );
+    assert_eq!(fixture.destination_file("motd.txt.old.orig"), "Old\n");
+}
+
+#[test]
+fn later_env_files_override_earlier_ones() {
+    let fixture = Fixture::new("env-layers");
+    fixture.source_file("contexts/survival/motd.txt", "{{greeting}} {{name}}\n");
+    fixture.commit();
+    let base = fixture.root.join("base.env");
+    let host = fixture.root.join("host.env");
+    write(&base, "greeting=Hello\nname=base\n").unwrap();
+    write(&host, "name=host\n").unwrap();
+
+    let files = format!("{},{}", base.display(), host.display());
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_ENV", &files)])
+        .success());
+
+    assert_eq!(fixture.destination_file("motd.txt"), "Hello host\n");
+}
+
+#[test]
+fn renders_nested_variables_from_vars_file() {
+    let fixture = Fixture::new("vars-file");
+    fixture
+        .source_file("vars.yml", "database:\n  host: db.internal\n  port: 5432\n")
+        .source_file(
+            "contexts/survival/db.properties",
+            "host={{database.host}}\nport={{database.port}}\n",
+        );
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_VARS_FILE", "vars.yml")])
+        .success());
+    assert_eq!(
+        fixture.destination_file("db.properties"),
+        "host=db.internal\nport=5432\n"
+    );
+}
+
+#[test]
+fn deep_merges_vars_files_in_order() {
+    let fixture = Fixture::new("vars-files");
+    fixture
+        .source_file(
+            "vars/a-database.yml",
+            "database:\n  host: db.internal\n  port: 5432\n",
+        )
+        .source_file("vars/b-network.yml", "network:\n  port: 25565\n")
+        .source_file("overrides.toml", "[database]\nhost = \"db.override\"\n")
+        .source_file(
+            "contexts/survival/server.properties",
+            "db={{database.host}}:{{database.port}}\nport={{network.port}}\n",
+        );
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[("SERVER_SYNC_VARS_FILE", "vars/*.yml, overrides.toml")]
+        )
+        .success());
+    assert_eq!(
+        fixture.destination_file("server.properties"),
+        "db=db.override:5432\nport=25565\n"
+    );
+}
+
+#[test]
+fn renders_template_helpers() {
+    let fixture = Fixture::new("helpers");
+    fixture.source_file(
+        "contexts/survival/helpers.txt",
+        "{{upper motd}} {{lower motd}} {{default missing \"fallback\"}} {{b64 motd}}\n",
+    );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(
+        fixture.destination_file("helpers.txt"),
+        "WELCOME welcome fallback V2VsY29tZQ==\n"
+    );
+}
+
+#[test]
+fn includes_shared_partials() {
+    let fixture = Fixture::new("partials");
+    fixture
+        .source_file(
+            "partials/common_header.txt",
+            "# Managed by ServerSync for {{server_name}}\n",
+        )
+        .source_file(
+            "contexts/survival/motd.txt",
+            "{{> common_header}}{{motd}}\n",
+        );
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(
+        fixture.destination_file("motd.txt"),
+        "# Managed by ServerSync for survival\nWelcome\n"
+    );
+}
+
+#[test]
+fn warns_when_vars_set_server_name() {
+    let fixture = Fixture::new("server-name-collision");
+    fixture
+        .source_file("contexts/survival/vars.toml", "server_name = \"lobby\"\n")
+        .source_file("contexts/survival/name.txt", "{{server_name}}\n")
+        .source_file("vars.yml", "server_name: lobby\n")
+        .source_file("contexts/creative/name.txt", "{{server_name}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[])
+        .output()
+        .expect("Run server_sync");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Variable server_name is set for context survival but will be overwritten"));
+    assert_eq!(fixture.destination_file("name.txt"), "survival\n");
+
+    let output = fixture
+        .command(&["creative"], &[("SERVER_SYNC_VARS_FILE", "vars.yml")])
+        .output()
+        .expect("Run server_sync");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Variable server_name is set for context creative but will be overwritten"));
+    assert_eq!(fixture.destination_file("name.txt"), "creative\n");
+
+    assert!(fixture
+        .command(&["survival"], &[])
+        .arg("--no-server-name")
+        .status()
+        .expect("Run server_sync")
+        .success());
+    assert_eq!(fixture.destination_file("name.txt"), "lobby\n");
+}
+
+#[test]
+fn context_vars_override_global_variables() {
+    let fixture = Fixture::new("context-vars");
+    fixture
+        .source_file("contexts/survival/vars.toml", "motd = \"Survival only\"\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/creative/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Survival only\n");
+    assert!(!fixture.destination.join("vars.toml").exists());
+
+    assert!(fixture.sync(&["creative"]).success());
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn syncignore_negations_re_include_files() {
+    let fixture = Fixture::new("syncignore-negation");
+    fixture
+        .source_file("contexts/survival/.syncignore", "*.log\n!keep.log\n")
+        .source_file("contexts/survival/debug.log", "debug\n")
+        .source_file("contexts/survival/keep.log", "keep\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert!(!fixture.destination.join("debug.log").exists());
+    assert_eq!(fixture.destination_file("keep.log"), "keep\n");
+}
+
+#[test]
+fn syncignore_directory_rules_skip_only_directories() {
+    let fixture = Fixture::new("syncignore-directories");
+    fixture
+        .source_file("contexts/survival/.syncignore", "cache/\n")
+        .source_file("contexts/survival/cache/data.txt", "data\n")
+        .source_file("contexts/survival/logs/cache", "file\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert!(!fixture.destination.join("cache").exists());
+    assert_eq!(fixture.destination_file("logs/cache"), "file\n");
+}
+
+#[test]
+fn context_syncignore_overrides_the_contexts_level_rules() {
+    let fixture = Fixture::new("syncignore-layers");
+    fixture
+        .source_file("contexts/.syncignore", "*.log\n")
+        .source_file("contexts/survival/.syncignore", "!server.log\n")
+        .source_file("contexts/survival/server.log", "server\n")
+        .source_file("contexts/survival/debug.log", "debug\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+    assert_eq!(fixture.destination_file("server.log"), "server\n");
+    assert!(!fixture.destination.join("debug.log").exists());
+}
+
+#[test]
+fn merge_manifest_overrides_the_extension_driver() {
+    let fixture = Fixture::new("merge-manifest");
+    fixture
+        .source_file(
+            "contexts/survival/.server-sync-merge",
+            "server.conf=yaml-deep\nserver.txt=properties\n",
+        )
+        .source_file("contexts/survival/server.conf", "motd: {{motd}}\n")
+        .source_file("contexts/survival/server.txt", "motd={{motd}}\n")
+        .existing_file("server.conf", "port: 25565\nmotd: Old\n")
+        .existing_file("server.txt", "port=25565\nmotd=Old\n");
+    fixture.commit();
+
+    assert!(fixture.sync(&["survival"]).success());
+
+    let conf = fixture.destination_file("server.conf");
+    assert!(conf.contains("port: 25565"));
+    assert!(conf.contains("motd: Welcome"));
+    assert_eq!(
+        fixture.destination_file("server.txt"),
+        "port=25565\nmotd=Welcome\n"
+    );
+}
+
+#[test]
+fn unknown_merge_drivers_fail() {
+    let fixture = Fixture::new("unknown-merge-driver");
+    fixture
+        .source_file(
+            "contexts/survival/.server-sync-merge",
+            "server.yml=yaml-shallow\n",
+        )
+        .source_file("contexts/survival/server.yml", "motd: {{motd}}\n");
+    fixture.commit();
+
+    assert!(!fixture.sync(&["survival"]).success());
+    assert!(!fixture.destination.join("server.yml").exists());
+
+    let output = fixture
+        .command(&["survival"], &[])
+        .arg("validate")
+        .output()
+        .expect("Run server_sync");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown merge driver yaml-shallow"));
+}
+
+#[test]
+fn keeps_going_past_a_failing_file() {
+    let fixture = Fixture::new("keep-going");
+    fixture
+        .source_file("contexts/survival/broken.json", r#"{"motd": "{{motd}}"}"#)
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .existing_file("broken.json", "{not json");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_KEEP_GOING", "true")])
+        .output()
+        .expect("Run server_sync");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Sync finished with 1 errors"));
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+    assert_eq!(fixture.destination_file("broken.json"), "{not json");
+}
+
+#[test]
+fn fail_fast_aborts_on_the_first_error() {
+    let fixture = Fixture::new("fail-fast");
+    fixture
+        .source_file("contexts/survival/broken.txt", "{{#if motd}}\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(
+            &["survival"],
+            &[("SERVER_SYNC_FAIL_FAST", "true"), ("SERVER_SYNC_JOBS", "1")],
+        )
+        .output()
+        .expect("Run server_sync");
+
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Sync finished with"));
+    assert!(!fixture.destination.join("motd.txt").exists());
+}
+
+#[test]
+fn failing_exports_are_collected_like_files() {
+    let fixture = Fixture::new("failing-export");
+    fixture
+        .source_file(
+            "contexts/survival/.server-sync-exports",
+            "broken=secrets/broken.txt\n",
+        )
+        .source_file("contexts/survival/secrets/broken.txt", "{{missing}}\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_KEEP_GOING", "true")])
+        .output()
+        .expect("Run server_sync");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("Render export broken"));
+    assert_eq!(fixture.destination_file("motd.txt"), "Welcome\n");
+}
+
+#[test]
+fn summarizes_what_the_sync_changed() {
+    let fixture = Fixture::new("summary");
+    fixture
+        .source_file("contexts/survival/new.txt", "{{motd}}\n")
+        .source_file("contexts/survival/motd.txt", "{{motd}}\n")
+        .source_file("contexts/survival/same.txt", "{{motd}}\n")
+        .existing_file("motd.txt", "Old\n")
+        .existing_file("same.txt", "Welcome\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[])
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(
+        "Summary: 1 created, 1 updated, 1 backed up, 1 unchanged, 0 skipped, 0 copied without templating, 0 pruned, 16 bytes written"
+    ));
+}
+
+#[test]
+fn prints_unified_diffs() {
+    let fixture = Fixture::new("unified-diff");
+    fixture
+        .source_file("contexts/survival/motd.txt", "{{motd}}\nport=25565\n")
+        .existing_file("motd.txt", "Old\nport=25565\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_DIFF_FORMAT", "unified")])
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    let destination = fixture.destination.join("motd.txt");
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!(
+        "--- {destination}\n+++ {destination}\n@@ -1,2 +1,2 @@\n-Old\n+Welcome\n port=25565\n",
+        destination = destination.display()
+    )));
+}
+
+#[test]
+fn json_logs_are_one_object_per_line() {
+    let fixture = Fixture::new("json-log");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    let output = fixture
+        .command(&["survival"], &[("SERVER_SYNC_LOG_FORMAT", "json")])
+        .output()
+        .expect("Run server_sync");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stdout
+        .lines()
+        .chain(stderr.lines())
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    assert!(!lines.is_empty());
+    for line in lines {
+        let record: serde_json::Value = serde_json::from_str(line)
+            .unwrap_or_else(|err| panic!("{line} isn't a JSON log line: {err}"));
+        for key in ["timestamp", "level", "target", "message"] {
+            assert!(record.get(key).is_some(), "{line} is missing {key}");
+        }
+    }
+}
+
+/// Accepts a single HTTP request on a local port and hands back its body.
+fn webhook_listener() -> (String, std::sync::mpsc::Receiver<String>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Bind webhook listener");
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Accept webhook request");
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("Read webhook header");
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().expect("Parse content length");
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).expect("Read webhook body");
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .expect("Reply to webhook");
+        sender.send(String::from_utf8(body).unwrap()).unwrap();
+    });
+
+    (url, receiver)
+}
+
+#[test]
+fn posts_the_summary_to_the_webhook() {
+    let fixture = Fixture::new("webhook");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+    let (url, body) = webhook_listener();
+
+    assert!(fixture
+        .sync_with(&["survival"], &[("SERVER_SYNC_WEBHOOK_URL", &url)])
+        .success());
+
+    let body = body
+        .recv_timeout(std::time::Duration::from_secs(10))
+        .expect("Receive webhook body");
+    let payload: serde_json::Value = serde_json::from_str(&body).expect("Parse webhook body");
+    assert_eq!(payload["status"], "success");
+    assert_eq!(payload["created"], 1);
+    assert!(payload["duration_secs"].is_number());
+    assert!(payload.get("error").is_none());
+    let destination = fixture.destination.join("motd.txt");
+    assert!(payload["changed_files"]
+        .as_array()
+        .expect("changed_files is a list")
+        .iter()
+        .any(|file| file.as_str() == destination.to_str()));
+}
+
+#[test]
+fn syncs_contexts_to_their_destination_overrides() {
+    let fixture = Fixture::new("destination-override");
+    fixture
+        .source_file("contexts/survival/survival.txt", "{{motd}}\n")
+        .source_file("contexts/creative/creative.txt", "{{motd}}\n");
+    fixture.commit();
+    let creative = fixture.root.join("creative");
+
+    assert!(fixture
+        .sync_with(
+            &["survival", "creative"],
+            &[("SERVER_SYNC_DEST_creative", creative.to_str().unwrap())],
+        )
+        .success());
+
+    assert_eq!(fixture.destination_file("survival.txt"), "Welcome\n");
+    assert_eq!(
+        read_to_string(creative.join("creative.txt")).unwrap(),
+        "Welcome\n"
+    );
+    assert!(!fixture.destination.join("creative.txt").exists());
+    assert!(!creative.join("survival.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn applies_configured_file_and_dir_modes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("configured-modes");
+    fixture.source_file("contexts/survival/config/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    assert!(fixture
+        .sync_with(
+            &["survival"],
+            &[
+                ("SERVER_SYNC_FILE_MODE", "600"),
+                ("SERVER_SYNC_DIR_MODE", "0o700")
+            ],
+        )
+        .success());
+
+    let mode = |path: &str| {
+        metadata(fixture.destination.join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(mode("config/motd.txt"), 0o600);
+    assert_eq!(mode("config"), 0o700);
+}
+
+#[test]
+fn rejects_invalid_modes() {
+    let fixture = Fixture::new("invalid-mode");
+    fixture.source_file("contexts/survival/motd.txt", "{{motd}}\n");
+    fixture.commit();
+
+    for (name, mode) in [
+        ("SERVER_SYNC_FILE_MODE", "689"),
+        ("SERVER_SYNC_DIR_MODE", "rwx"),
+    ] {
+        let output = fixture
+            .command(&["survival"], &[(name, mode)])
+            .arg("validate")
+            .output()
+            .expect("Run server_sync");
+
+        assert_eq!(output.status.code(), Some(19));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+            "Invalid mode {}, expected an octal mode like 644",
+            mode
+        )));
+    }
+    assert!(!fixture.destination.join("motd.txt").exists());
+}